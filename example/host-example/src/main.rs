@@ -1,5 +1,7 @@
 #![feature(async_closure)]
-use io_plugin_example::{Error, ExamplePluginHandle};
+use futures::{stream, StreamExt};
+use io_plugin::{CallError, RemoteError};
+use io_plugin_example::{Error, ExampleHostTrait, ExamplePluginHandle};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::{error::Error as StdError, path::PathBuf, str::FromStr};
@@ -10,9 +12,10 @@ use tokio::{
 
 #[main]
 async fn main() {
-    let mut plugin = (async || -> Result<_, Box<dyn StdError>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let plugin = (async || -> Result<_, Box<dyn StdError + Send + Sync>> {
         let path = PathBuf::from_str("target/debug/plugin-example")?;
-        Ok(ExamplePluginHandle::new(path).await?)
+        Ok(ExamplePluginHandle::new(path, Host).await?)
     })()
     .await
     .unwrap();
@@ -21,21 +24,29 @@ async fn main() {
         if line == "exit" {
             break;
         }
-        react_to_line(line, &mut plugin)
+        react_to_line(line, &plugin)
             .await
             .unwrap_or_else(|e| eprintln!("{e:#?}"));
         println!("\nInput desired action here:");
     }
 }
 
+struct Host;
+
+impl ExampleHostTrait for Host {
+    async fn get_config(&self, key: String) -> Result<Option<String>, RemoteError> {
+        Ok((key == "greeting").then(|| "Kia ora".to_string()))
+    }
+}
+
 lazy_static! {
     static ref NUMS_PARSER: Regex = Regex::new(r"-?[\d]+(:?\.\d+)?").unwrap();
 }
 
 async fn react_to_line(
     line: String,
-    plugin: &mut ExamplePluginHandle,
-) -> Result<(), Box<dyn StdError>> {
+    plugin: &ExamplePluginHandle,
+) -> Result<(), Box<dyn StdError + Send + Sync>> {
     let nums = NUMS_PARSER
         .find_iter(&line)
         .into_iter()
@@ -57,6 +68,44 @@ async fn react_to_line(
             .await?;
         println!("Got {} bytes!", bytes.len());
         return Ok(());
+    } else if line.starts_with("count ") {
+        let mut numbers = plugin
+            .count(
+                nums.get(0)
+                    .ok_or(Error::Generic("No number to count up to provided.".to_string()))?
+                    .to_string()
+                    .parse()?,
+            )
+            .await?;
+        while let Some(number) = numbers.next().await {
+            println!("{}", number?);
+        }
+        return Ok(());
+    } else if line.starts_with("send_bytes ") {
+        let amount: usize = nums
+            .get(0)
+            .ok_or(Error::Generic(
+                "You must specify the amount of bytes to send.".to_string(),
+            ))?
+            .to_string()
+            .parse()?;
+        let chunks = stream::iter((0..amount).step_by(1024))
+            .map(move |start| vec![0; 1024.min(amount - start)]);
+        println!("Plugin received {} bytes!", plugin.send_bytes(chunks).await?);
+        return Ok(());
+    } else if let Some(name) = line.strip_prefix("greet ") {
+        println!("{}", plugin.greet(name.to_string()).await?);
+        return Ok(());
+    } else if line.starts_with("add ") {
+        plugin
+            .add_to_state(
+                nums.get(0)
+                    .ok_or(Error::Generic("No amount to add provided.".to_string()))?
+                    .to_string()
+                    .parse()?,
+            )
+            .await?;
+        return Ok(());
     } else if line.starts_with("set ") {
         plugin
             .set_state(
@@ -69,8 +118,11 @@ async fn react_to_line(
         return Ok(());
     };
     if let [n1, n2] = nums[..] {
-        let result = plugin.op::<f64>(n1, n2).await?;
-        println!("Result: {result}");
+        match plugin.op::<f64>(n1, n2).await {
+            Ok(result) => println!("Result: {result}"),
+            Err(CallError::Remote(Error::MathError)) => println!("{n1} and {n2} can't be operated on"),
+            Err(err) => Err(err)?,
+        }
     } else {
     }
     Ok(())
@@ -1,4 +1,4 @@
-use io_plugin::{handle_doc, io_plugin};
+use io_plugin::{handle_doc, host_services, io_plugin};
 #[cfg(feature = "plugin")]
 use rand::{thread_rng, Rng};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
@@ -6,18 +6,39 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::error::Error as StdError;
 use thiserror::Error;
 
-#[io_plugin(plugin_trait = "plugin", handle = "host")]
+#[host_services(plugin_trait = "plugin", handle = "host")]
+pub enum ExampleHost {
+    ///Look up one of the host's configuration values
+    GetConfig(String, Option<String>),
+}
+
+#[io_plugin(plugin_trait = "plugin", handle = "host", host_services = ExampleHost)]
 #[derive(Clone)]
 #[handle_doc("async `ExamplePlugin` handle")]
 pub enum ExamplePlugin<T: DeserializeOwned + Serialize> {
     ///Get the name of this plugin
+    #[idempotent]
     GetName(String),
     SetState(i32, ()),
+    ///Add to the state, without waiting for the plugin to do so
+    #[notify]
+    AddToState(i32, ()),
+    #[idempotent]
     GetState(i32),
+    #[error_type(Error)]
     Op(f64, f64, T),
     ///Get `usize` random bytes from the plugin - used to simulate large data transfer
     #[implementation(gen_bytes)]
+    #[timeout_ms(10000)]
     RandomBytes(usize, Vec<u8>),
+    ///Count from 1 up to the given number, one response at a time
+    #[stream]
+    Count(u32, u32),
+    ///Upload bytes to the plugin in chunks - it responds with how many it received
+    #[client_stream]
+    SendBytes(Vec<u8>, usize),
+    ///Greet someone, as configured by the host
+    Greet(String, String),
 }
 
 #[derive(Error, Debug, Serialize, Deserialize)]
@@ -32,9 +53,14 @@ pub enum Error {
 pub async fn gen_bytes<T: DeserializeOwned + Serialize>(
     _plugin: &mut dyn ExamplePluginTrait<T>,
     amount: usize,
-) -> Result<Vec<u8>, Box<dyn StdError>> {
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+    let cancellation = io_plugin::CancellationToken::current();
     let mut vec = Vec::with_capacity(amount);
-    for _ in 0..amount {
+    for i in 0..amount {
+        // This loop never yields - so it has to check for itself whether the host is still waiting
+        if i % 4096 == 0 && cancellation.as_ref().is_some_and(|c| c.is_cancelled()) {
+            Err(Error::Generic("Cancelled".to_string()))?
+        }
         vec.push(thread_rng().gen())
     }
     Ok(vec)
@@ -1,5 +1,7 @@
-use io_plugin_example::{Error, ExamplePluginTrait};
-use std::{error::Error as StdError, ops::Div};
+use futures::{stream, Stream, StreamExt};
+use io_plugin::{RemoteError, RequestStream};
+use io_plugin_example::{Error, ExampleHostClient, ExamplePluginTrait};
+use std::ops::Div;
 use tokio::main;
 
 struct Plugin {
@@ -8,19 +10,19 @@ struct Plugin {
 
 impl ExamplePluginTrait<f64> for Plugin {
     #[doc = r"Get the name of this plugin"]
-    async fn get_name(&mut self) -> Result<String, Box<dyn StdError>> {
+    async fn get_name(&mut self) -> Result<String, RemoteError> {
         Ok("Division".to_string())
     }
 
-    async fn op(&mut self, arg1: f64, arg2: f64) -> Result<f64, Box<dyn StdError>> {
+    async fn op(&mut self, arg1: f64, arg2: f64) -> Result<f64, Error> {
         let intermediate = (arg1 as f64).div(arg2 as f64);
         if intermediate.is_nan() {
-            Err(Error::MathError)?;
+            return Err(Error::MathError);
         }
         Ok(intermediate)
     }
 
-    async fn set_state(&mut self, new_state: i32) -> Result<(), Box<dyn StdError>>
+    async fn set_state(&mut self, new_state: i32) -> Result<(), RemoteError>
     where
         Self: Sized,
     {
@@ -28,16 +30,46 @@ impl ExamplePluginTrait<f64> for Plugin {
         Ok(())
     }
 
-    async fn get_state(&mut self) -> Result<i32, Box<dyn StdError>>
+    async fn add_to_state(&mut self, amount: i32) -> Result<(), RemoteError> {
+        self.state = self
+            .state
+            .checked_add(amount)
+            .ok_or(Error::Generic("State overflowed".to_string()))?;
+        Ok(())
+    }
+
+    async fn get_state(&mut self) -> Result<i32, RemoteError>
     where
         Self: Sized,
     {
         Ok(self.state)
     }
+
+    fn count(&mut self, up_to: u32) -> impl Stream<Item = Result<u32, RemoteError>> {
+        stream::iter((1..=up_to).map(Ok))
+    }
+
+    async fn send_bytes(&mut self, mut chunks: RequestStream<Vec<u8>>) -> Result<usize, RemoteError> {
+        let mut received = 0;
+        while let Some(chunk) = chunks.next().await {
+            received += chunk?.len();
+        }
+        Ok(received)
+    }
+
+    async fn greet(&mut self, name: String) -> Result<String, RemoteError> {
+        let greeting = ExampleHostClient::current()?
+            .get_config("greeting".to_string())
+            .await?
+            .unwrap_or("Hello".to_string());
+        log::info!(name = name.as_str(); "Greeting");
+        Ok(format!("{greeting}, {name}!"))
+    }
 }
 
 #[main]
 async fn main() {
     // let bytes = io_plugin_example::gen_bytes(10).await;
+    io_plugin::PluginLogger::init(log::LevelFilter::Info).unwrap();
     Plugin { state: 0 }.main_loop().await
 }
@@ -9,7 +9,7 @@ use syn::{
     Arm, Attribute, ItemEnum, ItemImpl, Meta, MetaList, MetaNameValue, Type, Variant,
 };
 
-use crate::{util::get_doc, generics::enum_generics};
+use crate::{util::{chunk_type, get_doc}, generics::enum_generics};
 
 type EnumVariants = Punctuated<Variant, Comma>;
 
@@ -66,6 +66,10 @@ pub fn split_enum(input: &mut ItemEnum) -> (ItemEnum, ItemEnum, ItemImpl) {
         } else {
             parse_quote_spanned!(variant.span()=>#name)
         };
+        // A `#[client_stream]` variant's chunks are sent in their own frames, after the message
+        if chunk_type(variant).is_some() {
+            fields.pop();
+        }
         let message_types = fields
             .iter()
             .map(|f| f.ty.to_owned())
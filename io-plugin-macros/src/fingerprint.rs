@@ -0,0 +1,53 @@
+use quote::ToTokens;
+use syn::ItemEnum;
+
+use crate::util::{chunk_type, error_type, VariantKind};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// A stable fingerprint of an interface - an FNV-1a hash of the variant names, field types and kinds (e.g. `#[stream]`, or `#[client_stream]` along with its chunk type) and error types
+/// of its message and response enums.
+/// The enums' own names don't contribute, since they never appear on the wire.
+///
+/// Types are compared by name only - changing the definition of a type used in a variant doesn't change the fingerprint.
+pub fn fingerprint(original: &ItemEnum, message: &ItemEnum, response: &ItemEnum) -> u64 {
+    let kinds = original
+        .variants
+        .iter()
+        .map(|v| {
+            let kind = match chunk_type(v) {
+                Some(chunk) => format!("{:?}({})", VariantKind::of(v), chunk.to_token_stream()),
+                None => format!("{:?}", VariantKind::of(v)),
+            };
+            match error_type(v) {
+                Ok(Some(error)) => format!("{kind}!{}", error.to_token_stream()),
+                _ => kind,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical = [message, response]
+        .iter()
+        .map(|e| {
+            e.variants
+                .iter()
+                .map(|v| {
+                    let fields = v
+                        .fields
+                        .iter()
+                        .map(|f| f.ty.to_token_stream().to_string())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    format!("{}({fields})", v.ident)
+                })
+                .collect::<Vec<_>>()
+                .join(";")
+        })
+        .chain([kinds])
+        .collect::<Vec<_>>()
+        .join("|");
+    canonical.bytes().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
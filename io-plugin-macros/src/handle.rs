@@ -6,10 +6,13 @@ use regex::Regex;
 use std::{collections::HashSet, fmt::Display};
 use syn::{
     parse_quote, parse_quote_spanned, punctuated::Punctuated, spanned::Spanned, token::Comma,
-    FnArg, Generics, Ident, ImplItemFn, ItemEnum, ItemStruct, Type, Variant, Attribute,
+    FnArg, Generics, Ident, ImplItemFn, ItemEnum, ItemStruct, Path, Type, Variant, Attribute,
 };
 
-use crate::util::{get_doc, list_attr_by_id};
+use crate::{
+    host_services::services_item,
+    util::{chunk_type, error_type_or_default, get_doc, has_attr, list_attr_by_id, timeout_ms, VariantKind},
+};
 
 lazy_static! {
     pub static ref PASCAL_PARTS: Regex = Regex::new("[A-Z0-9_][a-z0-9_]+").unwrap();
@@ -35,6 +38,9 @@ pub fn generate_handle(
     message: ItemEnum,
     response: ItemEnum,
     gate: Option<Attribute>,
+    codec: TokenStream,
+    fingerprint: TokenStream,
+    services: Option<Path>,
 ) -> TokenStream {
     // let host_gate = generate_gate(gates.get("host"));
     let vis = &message.vis;
@@ -48,15 +54,14 @@ pub fn generate_handle(
     let methods = izip![&original.variants, &message.variants, &response.variants];
     let methods = methods
         .map(|(original, message, response)| {
-            let params = generate_method_args(original, message);
             generate_method(
                 original,
                 message,
                 message_ident,
                 response,
                 response_ident,
-                params,
                 generics,
+                true,
             )
         })
         .collect::<Vec<_>>();
@@ -79,10 +84,19 @@ pub fn generate_handle(
     let generated_host: ItemStruct = parse_quote_spanned!(message.span()=>
     #[doc = #handle_doc]
     #vis struct #name {
-            pub stdio: io_plugin::Mutex<io_plugin::ChildStdio>,
+            pub connection: io_plugin::Connection,
             pub name: std::string::String,
-            pub process: io_plugin::Child,
+            /// `None` if the handle doesn't know the plugin's process (see `from_stdio`)
+            pub process: Option<io_plugin::PluginProcess>,
+            /// The plugin's executable - empty unless the handle spawned it
             pub path: std::path::PathBuf,
+            /// The plugin's stderr, if it's being captured
+            pub stderr: Option<io_plugin::StderrLog>,
+            /// How the plugin was spawned - it's respawned the same way.
+            /// `None` if the handle didn't spawn it (see `from_child` and `from_stdio`), in which case it can't be respawned
+            pub command: Option<io_plugin::PluginCommand>,
+            pub options: io_plugin::SpawnOptions,
+            services: Option<io_plugin::ServiceHandler>,
         }
     );
 
@@ -96,6 +110,20 @@ pub fn generate_handle(
     } else {
         (quote!(name), Some(quote!(name: String)))
     };
+    let name_arg = name_param.as_ref().map(|_| quote!(name));
+    let (services_param, services_arg, services_handler) = match &services {
+        Some(services) => {
+            let services = services_item(services, "Trait");
+            (
+                Some(quote!(services: impl #services)),
+                Some(quote!(services)),
+                quote!(Some(#services::into_handler(services))),
+            )
+        }
+        None => (None, None, quote!(None)),
+    };
+    let params = name_param.iter().chain(&services_param).collect_vec();
+    let args = name_arg.iter().chain(&services_arg).collect_vec();
     let generics = &original.generics.params;
     let message_generics = message
         .generics
@@ -107,41 +135,213 @@ pub fn generate_handle(
         .type_params()
         .map(|g| g.ident.to_owned())
         .collect_vec();
+    // The helpers also take the error type of the variant being called
+    let helper_generics = generics
+        .iter()
+        .map(ToTokens::to_token_stream)
+        .chain([quote!(___Error___: io_plugin::Deserialise)])
+        .collect::<Punctuated<_, Comma>>();
+    let message_stream = original
+        .variants
+        .iter()
+        .any(|v| VariantKind::of(v) == VariantKind::Stream)
+        .then(|| quote!(
+            async fn message_stream<#helper_generics>(&self, message: #message_ident <#(#message_generics),*>, timeout: Option<std::time::Duration>) -> Result<io_plugin::ResponseStream<___Error___>, io_plugin::CallError<___Error___>> {
+                self.connection.stream(&message, timeout).await
+            }
+            fn stream_response<#helper_generics>(response: Result<io_plugin::Frame, io_plugin::CallError<___Error___>>) -> Result<#response_ident<#(#response_generics),*>, io_plugin::CallError<___Error___>> {
+                response?.decode_response()
+            }
+        ));
+    let message_notify = original
+        .variants
+        .iter()
+        .any(|v| VariantKind::of(v) == VariantKind::Notify)
+        .then(|| quote!(
+            async fn message_notify<#generics>(&self, message: #message_ident <#(#message_generics),*>, timeout: Option<std::time::Duration>) -> Result<(), io_plugin::CallError> {
+                self.connection.notify(&message, timeout).await
+            }
+        ));
+    let message_upload = original
+        .variants
+        .iter()
+        .any(|v| VariantKind::of(v) == VariantKind::ClientStream)
+        .then(|| quote!(
+            async fn message_upload<#helper_generics>(&self, message: #message_ident <#(#message_generics),*>, chunks: impl io_plugin::Stream<Item = impl io_plugin::Serialise>, timeout: Option<std::time::Duration>) -> Result<#response_ident<#(#response_generics),*>, io_plugin::CallError<___Error___>> {
+                self.connection.upload::<_, _, ___Error___>(&message, chunks, timeout).await?.decode_response()
+            }
+        ));
     let handle_impl = quote!(impl #name {
-        async fn message<#generics>(&mut self, message: #message_ident <#(#message_generics),*>) -> Result<#response_ident<#(#response_generics),*>, Box<dyn std::error::Error>> {
-            let stdio = &self.stdio;
-            let mut stdio = stdio.lock().await;
-            io_plugin::io_write_async(std::pin::pin!(&mut stdio.stdin), message).await?;
-            Ok(
-                io_plugin::io_read_async::<Result<_, io_plugin::IOPluginError>>(std::pin::pin!(
-                    &mut stdio.stdout
-                ))
-                .await??,
-            )
+        ///Fingerprint of the interface this handle was generated from - plugins must have been built with the same one
+        pub const FINGERPRINT: u64 = #fingerprint;
+
+        async fn message<#helper_generics>(&self, message: &#message_ident <#(#message_generics),*>, timeout: Option<std::time::Duration>) -> Result<#response_ident<#(#response_generics),*>, io_plugin::CallError<___Error___>> {
+            self.connection.call::<_, ___Error___>(message, timeout).await?.decode_response()
+        }
+        #message_stream
+        #message_upload
+        #message_notify
+        ///Retry calls to `#[idempotent]` variants which time out, as `policy` says
+        pub fn with_retry(mut self, policy: io_plugin::RetryPolicy) -> Self {
+            self.connection.set_retry_policy(Some(policy));
+            self
         }
-        pub async fn new(path: std::path::PathBuf, #name_param) -> Result<Self, Box<dyn std::error::Error>> {
-            let mut process = io_plugin::spawn_process(&path)?;
-            let stdio = process
+        ///Limit how long calls wait for the plugin, unless the variant called has its own `#[timeout_ms(..)]`
+        ///or the call is made within [`io_plugin::with_timeout`]
+        pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+            self.connection.set_timeout(Some(timeout));
+            self
+        }
+        ///Spawn the plugin - either the executable at a path, or as an [`io_plugin::PluginCommand`] says (e.g. with arguments, or capturing its stderr)
+        pub async fn new(command: impl Into<io_plugin::PluginCommand>, #(#params),*) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            Self::new_with_options(command, #(#args,)* Default::default()).await
+        }
+        ///Like [`Self::new`], but sends messages in `codec` rather than the interface's default
+        pub async fn new_with_codec(command: impl Into<io_plugin::PluginCommand>, #(#params,)* codec: io_plugin::CodecId) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let options = io_plugin::SpawnOptions { codec: Some(codec) };
+            Self::new_with_options(command, #(#args,)* options).await
+        }
+        ///Like [`Self::new`], but with control over how the handle talks to the plugin
+        pub async fn new_with_options(command: impl Into<io_plugin::PluginCommand>, #(#params,)* options: io_plugin::SpawnOptions) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let command = command.into();
+            let path = command.program().to_path_buf();
+            let services = #services_handler;
+            let (process, connection, stderr) = Self::spawn(&command, &options, services.clone()).await?;
+            connection.set_plugin(path.display());
+            Self {
+                process: Some(process),
+                connection,
+                name: "".to_string(),
+                path,
+                stderr,
+                command: Some(command),
+                options,
+                services,
+            }
+            .named(#name_arg)
+            .await
+        }
+        ///Attach to a plugin which has already been started (e.g. by another component), with its stdin and stdout piped.
+        ///Its stderr is captured if it's been piped too - it can't be respawned, though
+        pub async fn from_child(child: io_plugin::Child, #(#params),*) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let options = io_plugin::SpawnOptions::default();
+            let services = #services_handler;
+            let (process, connection, stderr) = Self::attach(child, "plugin", &options, services.clone()).await?;
+            Self {
+                process: Some(process),
+                connection,
+                name: "".to_string(),
+                path: Default::default(),
+                stderr,
+                command: None,
+                options,
+                services,
+            }
+            .named(#name_arg)
+            .await
+        }
+        ///Talk to a plugin over `reader` (its output) and `writer` (its input) - e.g. a socket, or an in-memory duplex.
+        ///Without a process, the handle can't tell how the plugin exited, nor respawn it
+        pub async fn from_stdio(
+            reader: impl io_plugin::AsyncRead + Send + 'static,
+            writer: impl io_plugin::AsyncWrite + Send + 'static,
+            #(#params),*
+        ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            let options = io_plugin::SpawnOptions::default();
+            let services = #services_handler;
+            let connection = Self::connect(Box::pin(reader), Box::pin(writer), &options, services.clone(), None).await?;
+            Self {
+                process: None,
+                connection,
+                name: "".to_string(),
+                path: Default::default(),
+                stderr: None,
+                command: None,
+                options,
+                services,
+            }
+            .named(#name_arg)
+            .await
+        }
+        ///Name the handle - and the plugin's log records and stderr lines after it
+        async fn named(self, #name_param) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+            #[allow(unused_mut)]
+            let mut handle = self;
+            handle.name = #name_expr;
+            handle.connection.set_plugin(&handle.name);
+            if let Some(stderr) = &handle.stderr {
+                stderr.set_plugin(&handle.name);
+            }
+            Ok(handle)
+        }
+        ///Spawn the plugin, and handshake with it
+        async fn spawn(
+            command: &io_plugin::PluginCommand,
+            options: &io_plugin::SpawnOptions,
+            services: Option<io_plugin::ServiceHandler>,
+        ) -> Result<(io_plugin::PluginProcess, io_plugin::Connection, Option<io_plugin::StderrLog>), Box<dyn std::error::Error + Send + Sync>> {
+            Self::attach(command.spawn()?, command.program().display(), options, services).await
+        }
+        ///Handshake with the plugin `process` over its stdin and stdout - capturing its stderr, if it's piped
+        async fn attach(
+            mut process: io_plugin::Child,
+            plugin: impl std::fmt::Display,
+            options: &io_plugin::SpawnOptions,
+            services: Option<io_plugin::ServiceHandler>,
+        ) -> Result<(io_plugin::PluginProcess, io_plugin::Connection, Option<io_plugin::StderrLog>), Box<dyn std::error::Error + Send + Sync>> {
+            let stderr = process
+                .stderr
+                .take()
+                .map(|stderr| io_plugin::StderrLog::capture(stderr, plugin, process.id()));
+            let (stdin, stdout) = process
                 .stdin
                 .take()
-                .and_then(|stdin| {
-                    Some(io_plugin::ChildStdio {
-                        stdin,
-                        stdout: process.stdout.take()?,
-                    })
-                })
+                .zip(process.stdout.take())
                 .ok_or(io_plugin::IOPluginError::InitialisationError(
                     "Stdin/stdout have not been piped".to_string(),
                 ))?;
-            #[allow(unused_mut)]
-            let mut handle = Self {
+            let process = io_plugin::PluginProcess::watch(process, stderr.clone());
+            let connection = Self::connect(Box::pin(stdout), Box::pin(stdin), options, services, Some(process.clone())).await?;
+            Ok((process, connection, stderr))
+        }
+        async fn connect(
+            reader: io_plugin::BoxedReader,
+            writer: io_plugin::BoxedWriter,
+            options: &io_plugin::SpawnOptions,
+            services: Option<io_plugin::ServiceHandler>,
+            process: Option<io_plugin::PluginProcess>,
+        ) -> Result<io_plugin::Connection, Box<dyn std::error::Error + Send + Sync>> {
+            io_plugin::Connection::connect(
+                reader,
+                writer,
+                options.codec.unwrap_or(#codec),
+                Self::FINGERPRINT,
+                services,
                 process,
-                stdio: io_plugin::Mutex::new(stdio),
-                name: "".to_string(),
-                path
-            };
-            handle.name = #name_expr;
-            Ok(handle)
+            )
+            .await
+        }
+        ///Stop the plugin - asking it to shut down (letting it finish the requests already sent), then terminating it,
+        ///then killing it, for as long as it keeps running - waiting up to `timeout` after each.
+        ///Returns how it exited - unless the handle doesn't know its process (see [`io_plugin::shutdown_process`])
+        pub async fn shutdown(&mut self, timeout: std::time::Duration) -> std::io::Result<Option<io_plugin::PluginExit>> {
+            io_plugin::shutdown_process(self.process.as_ref(), &self.connection, timeout).await
+        }
+        ///Whether the plugin is still running (or how it exited) - without making a call
+        pub fn status(&self) -> std::io::Result<io_plugin::PluginStatus> {
+            match &self.process {
+                Some(process) => process.status(),
+                None if self.connection.is_closed() => Ok(io_plugin::PluginStatus::Disconnected),
+                None => Ok(io_plugin::PluginStatus::Running),
+            }
+        }
+        ///What's been recorded about calls to each of the plugin's methods (by method name) - see [`io_plugin::MethodMetrics`]
+        pub fn metrics(&self) -> std::collections::HashMap<&'static str, io_plugin::MethodMetrics> {
+            self.connection.metrics()
+        }
+        ///The plugin's most recent stderr lines, oldest first - empty unless its stderr is being captured
+        pub fn recent_stderr(&self) -> Vec<String> {
+            self.stderr.as_ref().map(io_plugin::StderrLog::recent).unwrap_or_default()
         }
         #(#methods)*
     });
@@ -152,19 +352,57 @@ pub fn generate_handle(
         
         #gate
         #handle_impl
+
+        #gate
+        impl io_plugin::Respawn for #name {
+            fn connection(&self) -> &io_plugin::Connection {
+                &self.connection
+            }
+            async fn respawn(&self) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+                let Some(command) = &self.command else {
+                    return Err(io_plugin::IOPluginError::Other(
+                        "The plugin wasn't spawned by its handle, so it can't be respawned".to_string(),
+                    ).into());
+                };
+                // In case it's still running, but unresponsive
+                if let Some(process) = &self.process {
+                    process.start_kill();
+                }
+                let (process, mut connection, stderr) = Self::spawn(command, &self.options, self.services.clone()).await?;
+                connection.inherit(&self.connection);
+                if let Some(stderr) = &stderr {
+                    stderr.set_plugin(&self.name);
+                }
+                Ok(Self {
+                    process: Some(process),
+                    connection,
+                    name: self.name.clone(),
+                    path: self.path.clone(),
+                    stderr,
+                    command: Some(command.clone()),
+                    options: self.options.clone(),
+                    services: self.services.clone(),
+                })
+            }
+            async fn shutdown(&self, timeout: std::time::Duration) -> std::io::Result<Option<io_plugin::PluginExit>> {
+                io_plugin::shutdown_process(self.process.as_ref(), &self.connection, timeout).await
+            }
+        }
     )
 }
 
-fn generate_method(
+pub fn generate_method(
     original: &Variant,
     message: &Variant,
     message_type: &Ident,
     response: &Variant,
     response_type: &Ident,
-    params: Punctuated<FnArg, Comma>,
     generics: &Generics,
+    // Whether calls are recorded in the connection's metrics - only handles have one
+    tracked: bool,
 ) -> ImplItemFn {
     let name = format_ident!("{}", pascal_to_snake(original.ident.to_string()));
+    let params = generate_method_args(original, message);
     let message_variant_name = &message.ident;
     let response_variant_name = &response.ident;
 
@@ -222,6 +460,16 @@ fn generate_method(
     };
 
     let doc = get_doc(original);
+    let method_name = name.to_string();
+    let track = |call: TokenStream| match tracked {
+        true => quote!(self.connection.track(#method_name, #call)),
+        false => call,
+    };
+    let error = error_type_or_default(original);
+    let timeout = match timeout_ms(original) {
+        Ok(Some(ms)) => quote!(Some(std::time::Duration::from_millis(#ms))),
+        _ => quote!(None),
+    };
 
     let method_generics = {
         let generics = generics.type_params();
@@ -259,11 +507,54 @@ fn generate_method(
             .collect_vec()
     };
 
+    if VariantKind::of(original) == VariantKind::Stream {
+        let track_stream = track(quote!(self.message_stream::<#(#generics,)* #error>(#message_type::#message_variant_name/* */#message_fields, #timeout)));
+        return parse_quote_spanned!(original.ident.span()=>
+        #[allow(unreachable_patterns)]
+        #doc
+        pub async fn #name<#(#method_generics),*>(#params) -> Result<impl io_plugin::Stream<Item = Result<#return_type, io_plugin::CallError<#error>>>, io_plugin::CallError<#error>> {
+            let responses = #track_stream.await?;
+            Ok(io_plugin::StreamExt::map(responses, |response| match Self::stream_response::<#(#generics,)* #error>(response) {
+                Ok(#response_type::#response_variant_name/* */#response_fields) => #ok,
+                Err(e) => Err(e),
+                Ok(r) => {
+                    let res = std::fmt::format(
+                        format_args!(
+                            "Received {0}. Inappropriate variant",
+                            r.variant_name(),
+                        ),
+                    );
+                    Err(io_plugin::CallError::Decode(res))
+                }
+            }))
+        });
+    }
+
+    if VariantKind::of(original) == VariantKind::Notify {
+        let track_notify = track(quote!(self.message_notify::<#(#generics),*>(#message_type::#message_variant_name/* */#message_fields, #timeout)));
+        return parse_quote_spanned!(original.ident.span()=>
+        #doc
+        pub async fn #name<#(#method_generics),*>(#params) -> Result<(), io_plugin::CallError> {
+            #track_notify.await
+        });
+    }
+
+    let send = match chunk_type(original) {
+        Some(_) => {
+            let chunks = format_ident!("arg{}", message.fields.len() + 1);
+            quote!(self.message_upload::<#(#generics,)* #error>(message, #chunks, #timeout))
+        }
+        // Idempotent calls are retried as the handle's retry policy says - so the message is kept around to resend
+        None if has_attr(&original.attrs, "idempotent") => quote!(self.connection.retrying(|| self.message::<#(#generics,)* #error>(&message, #timeout))),
+        None => quote!(self.message::<#(#generics,)* #error>(&message, #timeout)),
+    };
+    let send = track(send);
     parse_quote_spanned!(original.ident.span()=>
     #[allow(unreachable_patterns)]
     #doc
-    pub async fn #name<#(#method_generics),*>(#params) -> Result<#return_type, Box<dyn std::error::Error>> {
-        let response = self.message::<#(#generics),*>(#message_type::#message_variant_name/* */#message_fields).await;
+    pub async fn #name<#(#method_generics),*>(#params) -> Result<#return_type, io_plugin::CallError<#error>> {
+        let message = #message_type::#message_variant_name/* */#message_fields;
+        let response = #send.await;
         match response {
             Ok(#response_type::#response_variant_name/* */#response_fields) => #ok,
             Err(e) => Err(e),
@@ -274,13 +565,13 @@ fn generate_method(
                         r.variant_name(),
                     ),
                 );
-                Err(res.into())
+                Err(io_plugin::CallError::Decode(res))
             }
         }
     })
 }
 
-fn generate_method_args(original: &Variant, message: &Variant) -> Punctuated<FnArg, Comma> {
+pub fn generate_method_args(original: &Variant, message: &Variant) -> Punctuated<FnArg, Comma> {
     let mut args = izip![&original.fields, &message.fields]
         .enumerate()
         .map(|(i, (original, message))| -> FnArg {
@@ -289,8 +580,12 @@ fn generate_method_args(original: &Variant, message: &Variant) -> Punctuated<FnA
             parse_quote_spanned!(original.span()=>#param: #ty)
         })
         .collect::<Punctuated<_, Comma>>();
+    if let Some(chunk) = chunk_type(original) {
+        let param = format_ident!("arg{}", args.len() + 1);
+        args.push(parse_quote_spanned!(original.span()=>#param: impl io_plugin::Stream<Item = #chunk>));
+    }
 
-    let arg = parse_quote!(&mut self);
+    let arg = parse_quote!(&self);
     args.insert(0, arg);
     args
 }
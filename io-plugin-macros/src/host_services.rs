@@ -0,0 +1,188 @@
+use itertools::{izip, Itertools};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use regex::Regex;
+use syn::{
+    parse_quote, parse_quote_spanned, punctuated::Punctuated, spanned::Spanned, token::Comma, Arm,
+    Attribute, Expr, ItemEnum, Pat, Path, TraitItemFn, Type,
+};
+
+use crate::{
+    handle::{generate_method, pascal_to_snake},
+    util::get_doc,
+};
+
+/// The item generated from a `#[host_services]` enum at `path`, named with `suffix` (e.g. `Trait`, `Message`)
+pub fn services_item(path: &Path, suffix: &str) -> Path {
+    let mut path = path.to_owned();
+    if let Some(last) = path.segments.last_mut() {
+        last.ident = format_ident!("{}{suffix}", last.ident);
+    }
+    path
+}
+
+pub fn generate_services(
+    original: ItemEnum,
+    message: ItemEnum,
+    response: ItemEnum,
+    client_gate: Option<Attribute>,
+    trait_gate: Option<Attribute>,
+    fingerprint: u64,
+) -> TokenStream {
+    let vis = &original.vis;
+    let services_name = &original.ident;
+    let trait_name = format_ident!("{}Trait", services_name);
+    let client_name = format_ident!("{}Client", services_name);
+    let message_name = &message.ident;
+    let response_name = &response.ident;
+    let variants = izip![&original.variants, &message.variants, &response.variants].collect_vec();
+
+    let methods = variants
+        .iter()
+        .map(|(original_v, message_v, response_v)| -> TraitItemFn {
+            let name = format_ident!("{}", pascal_to_snake(original_v.ident.to_string()));
+            let args = message_v.fields.iter().enumerate().map(|(i, f)| {
+                let name = format_ident!("arg{}", i + 1);
+                let ty = &f.ty;
+                quote!(#name: #ty)
+            });
+            let return_type: Type = {
+                let types = response_v
+                    .fields
+                    .iter()
+                    .map(|f| f.ty.to_owned())
+                    .collect::<Punctuated<_, Comma>>();
+                if let Some(ty) = types.first()
+                    && types.len() == 1
+                {
+                    ty.to_owned()
+                } else {
+                    parse_quote_spanned!(original_v.span()=>(#types))
+                }
+            };
+            let doc = get_doc(original_v);
+            parse_quote_spanned!(original_v.span()=>
+            #doc
+            fn #name(&self, #(#args),*) -> impl std::future::Future<Output = Result<#return_type, io_plugin::RemoteError>> + Send;)
+        })
+        .collect_vec();
+
+    let arms = variants
+        .iter()
+        .zip(&methods)
+        .map(|((original_v, message_v, response_v), method)| -> Arm {
+            let message_idents = (1..=message_v.fields.len())
+                .map(|i| format_ident!("arg{i}"))
+                .collect::<Punctuated<_, Comma>>();
+            let response_idents = (1..=response_v.fields.len())
+                .map(|i| format_ident!("arg{i}"))
+                .collect::<Punctuated<_, Comma>>();
+            let pat: Pat = {
+                let v = &message_v.ident;
+                if !message_idents.is_empty() {
+                    parse_quote!(#message_name::#v(#message_idents))
+                } else {
+                    parse_quote!(#message_name::#v)
+                }
+            };
+            let return_expr: Expr = {
+                let v = &response_v.ident;
+                if !response_idents.is_empty() {
+                    parse_quote!(#response_name::#v(#response_idents))
+                } else {
+                    parse_quote!(#response_name::#v)
+                }
+            };
+            let method_ident = &method.sig.ident;
+            let method_name = method_ident.to_string();
+            parse_quote_spanned!(original_v.span()=>
+            Ok(#pat) => match self.#method_ident(#message_idents).await {
+                #[allow(unused_parens)]
+                Ok((#response_idents)) => Ok(#return_expr),
+                Err(err) => Err(err.in_method(#method_name)),
+            },)
+        })
+        .collect_vec();
+
+    let client_methods = variants
+        .iter()
+        .map(|(original_v, message_v, response_v)| {
+            generate_method(
+                original_v,
+                message_v,
+                message_name,
+                response_v,
+                response_name,
+                &original.generics,
+                false,
+            )
+        })
+        .collect_vec();
+
+    let article = if Regex::new("^[aeiouAEIOU]")
+        .unwrap()
+        .is_match(&services_name.to_string())
+    {
+        "an"
+    } else {
+        "a"
+    };
+    let trait_doc = format!("The services the host provides to plugins through {article} `{services_name}` interface. To use, implement it on a struct, and pass it to the handle's `new`");
+    let client_doc = format!("Calls the host's `{services_name}` services from within a plugin - get one with [`{client_name}::current`]");
+
+    quote!(
+        impl #message_name {
+            ///Fingerprint of these services - part of the fingerprint of any interface which uses them
+            pub const FINGERPRINT: u64 = #fingerprint;
+        }
+
+        #trait_gate
+        #[doc = #trait_doc]
+        #vis trait #trait_name: Send + Sync + 'static {
+            #(#methods)*
+
+            ///Decode a plugin's request, pass it to the implementation, and encode its response
+            fn serve(&self, request: io_plugin::Frame) -> impl std::future::Future<Output = Result<io_plugin::Frame, io_plugin::IOPluginError>> + Send where Self: Sized { async move {
+                let message = request
+                    .decode::<#message_name>()
+                    .map_err(io_plugin::RemoteError::from);
+                let response: Result<#response_name, io_plugin::RemoteError> = match message {
+                    #(#arms)*
+                    Err(err) => Err(err),
+                };
+                io_plugin::Frame::encode(io_plugin::FrameKind::ServiceResponse, request.id, request.codec, &response)
+                    .map_err(|err| io_plugin::IOPluginError::Other(err.to_string()))
+            }}
+
+            ///Wrap this implementation up to be passed to [`io_plugin::Connection::connect`]
+            fn into_handler(self) -> io_plugin::ServiceHandler where Self: Sized {
+                let services = std::sync::Arc::new(self);
+                std::sync::Arc::new(move |request: io_plugin::Frame| -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<io_plugin::Frame, io_plugin::IOPluginError>> + Send>> {
+                    let services = services.clone();
+                    Box::pin(async move { services.serve(request).await })
+                })
+            }
+        }
+
+        #client_gate
+        #[doc = #client_doc]
+        #[derive(Clone)]
+        #vis struct #client_name {
+            pub host: io_plugin::HostConnection,
+        }
+
+        #client_gate
+        impl #client_name {
+            ///The client for the host which the plugin's main loop is serving
+            pub fn current() -> Result<Self, io_plugin::IOPluginError> {
+                Ok(Self { host: io_plugin::HostConnection::current()? })
+            }
+
+            async fn message<___Error___: io_plugin::Deserialise>(&self, message: &#message_name, _timeout: Option<std::time::Duration>) -> Result<#response_name, io_plugin::CallError<___Error___>> {
+                self.host.call_service::<_, ___Error___>(message).await?.decode_response()
+            }
+
+            #(#client_methods)*
+        }
+    )
+}
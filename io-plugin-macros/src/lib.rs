@@ -1,17 +1,20 @@
 #![feature(extend_one, let_chains, anonymous_lifetime_in_impl_trait, extract_if)]
 
-use std::collections::HashMap;
-
 use proc_macro::TokenStream;
 use quote::{format_ident, quote, quote_spanned, ToTokens};
-use syn::{spanned::Spanned, ItemEnum, Path, TraitItemFn, parse_macro_input};
+use syn::{spanned::Spanned, ItemEnum, Path, ReturnType, TraitItemFn, Type, parse_macro_input};
 
-use crate::{feature_gates::FeatureGates, util::generate_gate};
+use crate::{
+    feature_gates::FeatureGates,
+    util::{chunk_type, codec_id, error_type, generate_gate, has_attr, timeout_ms, VariantKind},
+};
 
 mod enums;
 mod feature_gates;
+mod fingerprint;
 mod generics;
 mod handle;
+mod host_services;
 mod plugin_interface;
 mod util;
 
@@ -24,19 +27,125 @@ mod util;
 ///
 /// Note that the enum this attribute applies to won't exist.  
 /// Instead, there will be a `message` enum, `response` enum, plugin `trait`, plugin `handle` (a struct) - postfixed with the highlighted words.
+///
+/// Messages are serialised with CBOR by default - `#[io_plugin(codec = "json")]` selects a different codec
+/// (one of `"cbor"`, `"json"`, `"msgpack"` or `"bincode"`, each gated behind the io-plugin feature of the same name).
+/// Handles can override it at construction, and plugins always respond in the codec they were messaged in.
+///
+/// By default, the plugin handles one request at a time, through `&mut self`. With `#[io_plugin(concurrency = N)]`,
+/// the plugin trait's methods take `&self` instead, and up to `N` requests are handled concurrently -
+/// each on a task of its own, with its response sent as soon as it's ready. So the methods' futures must be `Send`,
+/// and the plugin `Send + Sync + 'static`.
+///
+/// A panic within a plugin trait's method fails only the call which caused it, with `io_plugin::CallError::Panicked`,
+/// and the plugin keeps handling requests. With `#[io_plugin(on_panic = "exit")]`, it exits after telling the host instead.
+///
+/// Variants marked `#[stream]` respond with a stream of outputs rather than a single one -
+/// the plugin trait's method returns an `impl io_plugin::Stream`, and so does the handle's (once the request has been sent).
+///
+/// Variants marked `#[client_stream]` take a stream as their last input field - for uploading large inputs in chunks.
+/// The field's type is the chunk type: the handle's method takes an `impl io_plugin::Stream` of it,
+/// and the plugin trait's method receives an [`io_plugin::RequestStream`] of it.
+/// Only a few chunks are sent ahead of what the plugin has consumed (see [`io_plugin::CHUNK_WINDOW`]).
+///
+/// Variants marked `#[notify]` are one-way - they must output `()`, and the handle's method returns as soon as the message
+/// has been written, rather than waiting for the plugin to handle it. Errors from the plugin's method are logged with `log::error!` (which [`io_plugin::PluginLogger`] forwards to the host).
+///
+/// Calls wait for the plugin for as long as it takes, unless a timeout applies - from the innermost of
+/// [`io_plugin::with_timeout`] around the call, the variant's `#[timeout_ms(N)]`, or the handle's `with_timeout`.
+/// A call which times out fails with [`io_plugin::CallError::Timeout`], and is cancelled - so the handle remains usable.
+///
+/// Variants marked `#[idempotent]` are safe to call more than once - so with the handle's `with_retry`, their calls are retried
+/// (as the [`io_plugin::RetryPolicy`] says) if they time out. Calls to other variants are never retried.
+///
+/// The plugin trait's methods fail with an [`io_plugin::RemoteError`], which any error converts into (so `?` just works).
+/// It carries the error's `source()` chain, its kind (where known), the method's name, and a backtrace if they're enabled in the plugin.
+/// The handle's methods fail with an [`io_plugin::CallError`] - either the plugin's error, or why the call didn't complete.
+/// Variants marked `#[error_type(E)]` fail with `E` instead (which must be serialisable) - so the host receives
+/// a `CallError<E>`, and can match on the plugin's error as is.
+///
+/// With `#[io_plugin(host_services = MyServices)]`, plugins can call the services of a [`macro@host_services`] enum on the host
+/// (through its client) while handling requests, and the handle's `new` takes the host's implementation of them.
+///
+/// When a handle is created, it exchanges a handshake with the plugin, carrying the io-plugin protocol version
+/// and a fingerprint of this enum's variants - so a plugin built against a different version of the interface
+/// is rejected with [`io_plugin::IOPluginError::IncompatibleInterface`], rather than failing to decode messages later.
 #[proc_macro_attribute]
 pub fn io_plugin(attribute_data: TokenStream, input: TokenStream) -> TokenStream {
     let gates = syn::parse::<FeatureGates>(attribute_data).ok();
-    let gates = gates.map(|g| g.hashmap()).unwrap_or(HashMap::new());
+    let gates = gates.map(|g| g.hashmap()).unwrap_or_default();
     let mut input = parse_macro_input!(input as ItemEnum);
 
+    let codec = match codec_id(gates.get("codec")) {
+        Ok(codec) => codec,
+        Err(err) => return quote_spanned!(input.ident.span()=>compile_error!(#err);).into(),
+    };
+    let concurrency = match gates.get("concurrency").map(|limit| limit.parse::<usize>()) {
+        None => None,
+        Some(Ok(limit)) if limit > 0 => Some(limit),
+        Some(_) => {
+            return quote_spanned!(input.ident.span()=>compile_error!("`concurrency` must be a positive integer");).into()
+        }
+    };
+    let panic_policy = match gates.get("on_panic").map(|policy| policy.trim_matches('"').to_owned()).as_deref() {
+        None | Some("continue") => quote!(io_plugin::PanicPolicy::Continue),
+        Some("exit") => quote!(io_plugin::PanicPolicy::Exit),
+        Some(_) => {
+            return quote_spanned!(input.ident.span()=>compile_error!("`on_panic` must be \"continue\" or \"exit\"");).into()
+        }
+    };
+
     if let Some(lifetime) = input.generics.lifetimes().last() {
         return quote_spanned!(lifetime.span()=>compile_error!("lifetimes are not supported in `io_plugin`");).into();
     }
 
+    for variant in &input.variants {
+        if VariantKind::ATTRIBUTES.iter().filter(|id| has_attr(&variant.attrs, id)).count() > 1 {
+            return quote_spanned!(variant.span()=>compile_error!("a variant can only be one of `#[stream]`, `#[client_stream]` or `#[notify]`");).into();
+        }
+        if let Err(err) = timeout_ms(variant) {
+            return quote_spanned!(variant.span()=>compile_error!(#err);).into();
+        }
+        match error_type(variant) {
+            Err(err) => return quote_spanned!(variant.span()=>compile_error!(#err);).into(),
+            Ok(Some(_)) if VariantKind::of(variant) == VariantKind::Notify => {
+                return quote_spanned!(variant.span()=>compile_error!("`#[notify]` variants can't have an `error_type`, since their errors aren't sent to the host");).into()
+            }
+            Ok(_) => {}
+        }
+        if VariantKind::of(variant) == VariantKind::Notify
+            && !variant.fields.iter().next_back().is_some_and(|f| matches!(&f.ty, Type::Tuple(t) if t.elems.is_empty()))
+        {
+            return quote_spanned!(variant.span()=>compile_error!("`#[notify]` variants must output `()`");).into();
+        }
+        if has_attr(&variant.attrs, "idempotent") && VariantKind::of(variant) != VariantKind::Unary {
+            return quote_spanned!(variant.span()=>compile_error!("only variants which respond once can be `#[idempotent]` - not `#[stream]`, `#[client_stream]` or `#[notify]` ones");).into();
+        }
+        if has_attr(&variant.attrs, "client_stream") && chunk_type(variant).is_none() {
+            return quote_spanned!(variant.span()=>compile_error!("`#[client_stream]` variants need a chunk field before the output field");).into();
+        }
+    }
+
     input.ident = format_ident!("{}", input.ident.to_string().trim_start_matches("_"));
 
+    let services = match gates.get("host_services").map(|services| syn::parse_str::<Path>(services)) {
+        None => None,
+        Some(Ok(services)) => Some(services),
+        Some(Err(_)) => {
+            return quote_spanned!(input.ident.span()=>compile_error!("`host_services` must be the path of a `#[host_services]` enum");).into()
+        }
+    };
+
     let (message, response, response_impl) = enums::split_enum(&mut input);
+    let fingerprint = fingerprint::fingerprint(&input, &message, &response);
+    // The host's services are part of the interface too
+    let fingerprint = match &services {
+        Some(services) => {
+            let services = host_services::services_item(services, "Message");
+            quote!((#fingerprint ^ #services::FINGERPRINT.rotate_left(1)))
+        }
+        None => quote!(#fingerprint),
+    };
 
     for ty in input.generics.type_params_mut() {
         ty.default = None;
@@ -48,12 +157,25 @@ pub fn io_plugin(attribute_data: TokenStream, input: TokenStream) -> TokenStream
         message.clone(),
         response.clone(),
         generate_gate(gates.get("handle")),
+        codec,
+        fingerprint.clone(),
+        services,
     );
 
     let gate = gates.get("plugin_trait");
-    let (plugin_trait, main_loop_iteration) =
-        plugin_interface::generate_trait(input.clone(), message.clone(), response.clone(), gate);
+    let (plugin_trait, main_loop_functions) = plugin_interface::generate_trait(
+        input.clone(),
+        message.clone(),
+        response.clone(),
+        gate,
+        fingerprint,
+        concurrency,
+        panic_policy,
+    );
     let plugin_trait_gate = generate_gate(gate);
+    let main_loop_functions = main_loop_functions
+        .iter()
+        .map(|function| quote!(#plugin_trait_gate #function));
 
     quote_spanned!(message.span()=>
     #message
@@ -64,14 +186,67 @@ pub fn io_plugin(attribute_data: TokenStream, input: TokenStream) -> TokenStream
     #plugin_trait_gate
     #plugin_trait
 
-    #plugin_trait_gate
-    #main_loop_iteration
+    #(#main_loop_functions)*
 
     #handle
     )
     .into()
 }
 
+/// Generate the services a host provides to its plugins, based on an enum definition for their operations -
+/// pass the enum's path to [`io_plugin`] as `#[io_plugin(host_services = MyServices)]` to make them available to that interface's plugins.
+/// From the host's perspective - input types are all fields except the last one, and the output type is the last one.
+///
+/// As with [`io_plugin`], the enum itself won't exist. Instead, there will be a `message` enum, `response` enum,
+/// a `trait` (which the host implements, and passes to the handle's `new`) and a `client` (which plugins call the host through) -
+/// postfixed with the highlighted words. The handle's `new` takes the implementation, after the plugin's name.
+///
+/// `handle = "feature"` gates the trait, and `plugin_trait = "feature"` gates the client - the same as the features passed to [`io_plugin`].
+/// Requests to the host's services are multiplexed over the same pipes as requests to the plugin, so they can be made from within the plugin's methods.
+/// `#[stream]`, `#[client_stream]`, `#[notify]`, `#[timeout_ms]`, `#[error_type]`, `#[idempotent]` and generics aren't supported.
+#[proc_macro_attribute]
+pub fn host_services(attribute_data: TokenStream, input: TokenStream) -> TokenStream {
+    let gates = syn::parse::<FeatureGates>(attribute_data).ok();
+    let gates = gates.map(|g| g.hashmap()).unwrap_or_default();
+    let mut input = parse_macro_input!(input as ItemEnum);
+
+    if let Some(param) = input.generics.params.first() {
+        return quote_spanned!(param.span()=>compile_error!("generics are not supported in `host_services`");).into();
+    }
+    for variant in &input.variants {
+        if VariantKind::of(variant) != VariantKind::Unary
+            || has_attr(&variant.attrs, "timeout_ms")
+            || has_attr(&variant.attrs, "error_type")
+            || has_attr(&variant.attrs, "idempotent")
+        {
+            return quote_spanned!(variant.span()=>compile_error!("`#[stream]`, `#[client_stream]`, `#[notify]`, `#[timeout_ms]`, `#[error_type]` and `#[idempotent]` are not supported in `host_services`");).into();
+        }
+    }
+
+    input.ident = format_ident!("{}", input.ident.to_string().trim_start_matches("_"));
+
+    let (message, response, response_impl) = enums::split_enum(&mut input);
+    let fingerprint = fingerprint::fingerprint(&input, &message, &response);
+    let services = host_services::generate_services(
+        input.clone(),
+        message.clone(),
+        response.clone(),
+        generate_gate(gates.get("plugin_trait")),
+        generate_gate(gates.get("handle")),
+        fingerprint,
+    );
+
+    quote_spanned!(message.span()=>
+    #message
+
+    #response
+    #response_impl
+
+    #services
+    )
+    .into()
+}
+
 /// Allows customising the documentation of the handle generated by [`io_plugin`]
 #[proc_macro_attribute]
 pub fn handle_doc(_attr: TokenStream, item: TokenStream) -> TokenStream {
@@ -110,9 +285,18 @@ pub fn trait_method_default(attr: TokenStream, item: TokenStream) -> TokenStream
     });
     let implementation = parse_macro_input!(attr as Path);
     method.semi_token = None;
+    // `#[stream]` methods return a stream rather than a future - the implementation is called as-is
+    let returns_stream = matches!(&signature.output, ReturnType::Type(_, ty)
+        if ty.to_token_stream().to_string().starts_with("impl io_plugin :: Stream"));
+    if returns_stream {
+        return quote!(#signature {
+            #implementation(self, #(#args),*)
+        })
+        .into();
+    }
     quote!(#signature {
         async move {
-            #implementation(self, #(#args),*).await
+            #implementation(self, #(#args),*).await.map_err(Into::into)
         }
     })
     .into()
@@ -1,4 +1,5 @@
 use itertools::{izip, Itertools};
+use proc_macro2::TokenStream;
 use quote::{format_ident, quote};
 use syn::{
     parse_quote, parse_quote_spanned, punctuated::Punctuated, spanned::Spanned, token::Comma, Arm,
@@ -7,7 +8,7 @@ use syn::{
 
 use crate::{
     handle::pascal_to_snake,
-    util::{get_doc, list_attr_by_id},
+    util::{chunk_type, error_type, error_type_or_default, get_doc, list_attr_by_id, VariantKind},
 };
 
 pub fn generate_trait(
@@ -15,9 +16,18 @@ pub fn generate_trait(
     message: ItemEnum,
     response: ItemEnum,
     gate: Option<&String>,
-) -> (ItemTrait, ItemFn) {
+    fingerprint: TokenStream,
+    concurrency: Option<usize>,
+    panic_policy: TokenStream,
+) -> (ItemTrait, Vec<ItemFn>) {
     let name = format_ident!("{}Trait", original.ident);
     let vis = &original.vis;
+    // Requests handled concurrently are each handled on a task of their own - so their futures must be `Send`
+    let (receiver, receiver_type, send) = if concurrency.is_some() {
+        (quote!(&self), quote!(&___Plugin___), Some(quote!(+ Send)))
+    } else {
+        (quote!(&mut self), quote!(&mut ___Plugin___), None)
+    };
     let variants = izip![
         original.variants.to_owned(),
         message.variants.to_owned(),
@@ -30,7 +40,7 @@ pub fn generate_trait(
         .map(|(original_v, message_v, response_v)| {
             let name = format_ident!("{}", pascal_to_snake(original_v.ident.to_string()));
 
-            let args = message_v
+            let mut args = message_v
                 .fields
                 .iter()
                 .enumerate()
@@ -40,6 +50,10 @@ pub fn generate_trait(
                     (parse_quote_spanned! {f.span()=>#name}, ty.to_owned())
                 })
                 .collect_vec();
+            if let Some(chunk) = chunk_type(original_v) {
+                let name = format_ident!("arg{}", args.len() + 1);
+                args.push((name, parse_quote_spanned!(original_v.span()=>io_plugin::RequestStream<#chunk>)));
+            }
             // let arg_idents = args.iter().map(|(id, _)| id).collect_vec();
             let fn_args = args.iter().map(|(id, ty)| quote!(#id: #ty));
 
@@ -59,9 +73,14 @@ pub fn generate_trait(
             };
             let doc = get_doc(original_v);
 
+            let error = error_type_or_default(original_v);
+            let output = match VariantKind::of(original_v) {
+                VariantKind::Unary | VariantKind::ClientStream | VariantKind::Notify => quote!(std::future::Future<Output = Result<#return_type, #error>> #send),
+                VariantKind::Stream => quote!(io_plugin::Stream<Item = Result<#return_type, #error>> #send),
+            };
             let mut method: TraitItemFn = parse_quote_spanned!(original_v.span()=>
             #doc
-            fn #name(&mut self, #(#fn_args),*) -> impl std::future::Future<Output = Result<#return_type, Box<dyn std::error::Error>>> where Self: Sized;);
+            fn #name(#receiver, #(#fn_args),*) -> impl #output where Self: Sized;);
             if let Some((_, content)) = list_attr_by_id(original_v.attrs.as_slice(), "implementation") 
             {
                 method.attrs.extend_one(
@@ -81,6 +100,12 @@ pub fn generate_trait(
         .type_params()
         .map(|t| t.ident.to_owned())
         .collect_vec();
+    let response_name = &response.ident;
+    let response_generics = &response
+        .generics
+        .type_params()
+        .map(|t| t.ident.to_owned())
+        .collect_vec();
 
     let arms = variants
         .iter()
@@ -119,15 +144,58 @@ pub fn generate_trait(
                 }
             };
             let method_ident = &method.sig.ident;
-            let arm = parse_quote_spanned!(original_v.span()=>
-            #pat => {
-                match plugin.#method_ident(#message_idents).await {
-                    #[allow(unused_parens)]
-                    Ok((#response_idents)) => Ok(#return_expr),
-                    Err(err) => Err(io_plugin::IOPluginError::Other(err.to_string())),
+            // Errors of the default type record which method returned them
+            let method_name = method_ident.to_string();
+            let tag_error = match error_type(original_v) {
+                Ok(Some(_)) => quote!(err),
+                _ => quote!(err.in_method(#method_name)),
+            };
+            let error = error_type_or_default(original_v);
+            let mut call_args = message_idents.clone();
+            let mut take_chunks = None;
+            if VariantKind::of(original_v) == VariantKind::ClientStream {
+                call_args.push(format_ident!("chunks"));
+                take_chunks = Some(quote!(let chunks = host.request_stream(request);));
+            }
+            match VariantKind::of(original_v) {
+                VariantKind::Unary | VariantKind::ClientStream => parse_quote_spanned!(original_v.span()=>
+                #pat => io_plugin::method_span!(#method_name, async {
+                    io_plugin::HostConnection::record_method(#method_name);
+                    #take_chunks
+                    let response: Result<#response_name<#(#response_generics),*>, #error> = match plugin.#method_ident(#call_args).await {
+                        #[allow(unused_parens)]
+                        Ok((#response_idents)) => Ok(#return_expr),
+                        Err(err) => Err(#tag_error),
+                    };
+                    host.respond(request, io_plugin::FrameKind::Response, &response).await
+                }).await,),
+                VariantKind::Notify => {
+                    let variant_name = original_v.ident.to_string();
+                    parse_quote_spanned!(original_v.span()=>
+                    #pat => io_plugin::method_span!(#method_name, async {
+                        io_plugin::HostConnection::record_method(#method_name);
+                        // Nobody's waiting for a response - so errors are only logged (which PluginLogger forwards to the host)
+                        if let Err(err) = plugin.#method_ident(#message_idents).await {
+                            io_plugin::log::error!("`{}` notification failed: {err}", #variant_name);
+                        }
+                        Ok(())
+                    }).await,)
                 }
-            });
-            arm
+                VariantKind::Stream => parse_quote_spanned!(original_v.span()=>
+                #pat => io_plugin::method_span!(#method_name, async {
+                    io_plugin::HostConnection::record_method(#method_name);
+                    let mut items = std::pin::pin!(plugin.#method_ident(#message_idents));
+                    while let Some(item) = io_plugin::StreamExt::next(&mut items).await {
+                        let item: Result<#response_name<#(#response_generics),*>, #error> = match item {
+                            #[allow(unused_parens)]
+                            Ok((#response_idents)) => Ok(#return_expr),
+                            Err(err) => Err(#tag_error),
+                        };
+                        host.respond_item(request, &item).await?;
+                    }
+                    host.respond(request, io_plugin::FrameKind::StreamEnd, &()).await
+                }).await,),
+            }
         })
         .collect::<Vec<_>>();
 
@@ -146,40 +214,109 @@ pub fn generate_trait(
         ty.default = None;
     }
     let generics = generics.type_params().collect_vec();
-    let generic_idents = generics.iter().map(|p| p.ident.to_owned());
+    let generic_idents = generics.iter().map(|p| p.ident.to_owned()).collect_vec();
 
+    // Requests handled concurrently share the plugin between tasks
+    let (serve_bounds, serve_doc) = if let Some(limit) = concurrency {
+        (
+            quote!(Self: Sized + Send + Sync + 'static, #(#generic_idents: Send + Sync + 'static,)*),
+            format!("\nUp to {limit} requests are handled at once"),
+        )
+    } else {
+        (quote!(Self: Sized), String::new())
+    };
+    let handle_requests = if let Some(limit) = concurrency {
+        quote! {
+            let plugin = std::sync::Arc::new(self);
+            let dispatching = (plugin.clone(), host.clone());
+            io_plugin::serve_concurrently(&host, requests, #limit, move |request| {
+                let (plugin, host) = dispatching.clone();
+                async move { __dispatch::<#(#generic_idents,)* Self>(&plugin, &request, &host).await }
+            })
+            .await;
+            // Every request has been handled, so nothing else holds the plugin
+            if let Ok(mut plugin) = std::sync::Arc::try_unwrap(plugin) {
+                plugin.on_shutdown().await;
+            }
+        }
+    } else {
+        quote! {
+            let mut requests = requests;
+            while let Some(request) = requests.recv().await {
+                host.handle(&request, __dispatch(&mut self, &request, &host)).await;
+            }
+            self.on_shutdown().await;
+        }
+    };
+    let serve_doc = format!("Serve the host at the other end of `reader` (the host's output) and `writer` (its input) - e.g. a socket, or an in-memory duplex.\nHandshakes with the host, then handles its requests until it closes the pipe (or asks the plugin to shut down), then calls [`Self::on_shutdown`].{serve_doc}");
+    let serve: TraitItemFn = parse_quote!(
+        #[doc = #serve_doc]
+        fn serve(
+            #[allow(unused_mut)] mut self,
+            reader: impl io_plugin::AsyncRead + Send + 'static,
+            writer: impl io_plugin::AsyncWrite + Send + 'static,
+        ) -> impl std::future::Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>
+        where
+            #serve_bounds
+        { async move {
+            let mut reader: io_plugin::BoxedReader = Box::pin(reader);
+            let mut writer: io_plugin::BoxedWriter = Box::pin(writer);
+            let codec = io_plugin::plugin_handshake(reader.as_mut(), writer.as_mut(), #fingerprint).await?;
+            let (host, requests) = io_plugin::HostConnection::start(reader, writer, codec);
+            host.set_panic_policy(#panic_policy);
+            #handle_requests
+            host.close().await;
+            Ok(())
+        }}
+    );
+    let main_loop: TraitItemFn = parse_quote!(
+        ///Generally, you'd want to call this in the "main" func - as this starts the plugin, serving the host over stdin and stdout
+        ///(see [`Self::serve`]). Exits the process once the host is done with the plugin
+        fn main_loop(self) -> impl std::future::Future<Output = ()>
+        where
+            #serve_bounds
+        { async move {
+            match self.serve(io_plugin::stdin(), io_plugin::stdout()).await {
+                Ok(()) => {
+                    eprintln!("Host closed");
+                    std::process::exit(0);
+                }
+                Err(err) => {
+                    eprintln!("Handshake with host failed: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }}
+    );
+
+    let functions = vec![parse_quote!(
+        ///Only used internally to decode a request, pass it to the plugin, and send the plugin's response(s)
+        async fn __dispatch <#(#generics,)* ___Plugin___: #name<#(#generic_idents),*>> (plugin: #receiver_type, request: &io_plugin::Frame, host: &io_plugin::HostConnection) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            match request.decode::<#message_name <#(#message_generics),*>>() {
+                Ok(message) => match message {
+                    #(#arms)*
+                },
+                Err(err) if request.kind == io_plugin::FrameKind::Notification => Err(err),
+                Err(err) => {
+                    let response: Result<(), _> = Err(io_plugin::RemoteError::from(err));
+                    host.respond(request, io_plugin::FrameKind::Response, &response).await
+                }
+            }
+        }
+    )];
     (
         parse_quote_spanned!(original.span()=>
         #[doc=#plugin_trait_doc]
         #vis trait #name <#(#generics),*> {
             #(#methods)*
-            ///Generally, you'd want to call this in the "main" func - as this starts the plugin
-            fn main_loop(mut self) -> impl std::future::Future<Output = ()> where Self: Sized { async move {
-                    let mut stdin = io_plugin::stdin();
-                    let mut stdout = io_plugin::stdout();
-
-                    loop {
-                        __main_loop_iteration(&mut self, &mut stdin, &mut stdout).await
-                        .unwrap_or_else(|err| {
-                            if let Some(&io_plugin::IOPluginError::PipeClosed) =
-                                err.downcast_ref::<io_plugin::IOPluginError>()
-                            {
-                                eprintln!("Host closed");
-                                std::process::exit(0);
-                            }
-                            eprintln!("{err:#?}")
-                        });
-                    }
-                }}
+            ///Called once the host has asked the plugin to shut down (or closed the pipes), and every request has been handled -
+            ///just before the plugin exits
+            fn on_shutdown(&mut self) -> impl std::future::Future<Output = ()> where Self: Sized {
+                async {}
+            }
+            #serve
+            #main_loop
         }),
-        parse_quote!(///Only used internally to iterate through recieving and then responding to messages. Call [`self::main_loop`] instead
-        async fn __main_loop_iteration <#(#generics),*, ___Plugin___: #name<#(#generic_idents),*>> (plugin: &mut ___Plugin___, stdin: &mut io_plugin::Stdin, stdout: &mut io_plugin::Stdout) -> Result<(), Box<dyn std::error::Error>> {
-                let message: #message_name <#(#message_generics),*> = io_plugin::io_read_async(std::pin::pin!(stdin.get_mut())).await?;
-                let response = match message {
-                    #(#arms)*
-                };
-                io_plugin::io_write_async(std::pin::pin!(stdout), response).await?;
-                Ok(())
-            }),
+        functions,
     )
 }
@@ -1,7 +1,7 @@
 use std::fmt::Display;
 
 use quote::quote;
-use syn::{parse_quote, Attribute, Expr, Ident, Meta, MetaList, MetaNameValue, Variant};
+use syn::{parse_quote, Attribute, Expr, Ident, Meta, MetaList, MetaNameValue, Type, Variant};
 
 pub fn list_attr_by_id(
     original: &[Attribute],
@@ -46,3 +46,102 @@ pub fn generate_gate(gate: Option<impl Display>) -> Option<Attribute> {
     let gate = gate.trim_matches('"');
     Some(parse_quote!(#[cfg(feature = #gate)]))
 }
+
+/// Resolve the `codec = "..."` argument of `io_plugin` to the codec's ID.
+/// Refers to the codec's type (rather than just the [`io_plugin::CodecId`] variant), so that
+/// using a codec whose feature isn't enabled is a compile-time error
+pub fn codec_id(codec: Option<impl Display>) -> Result<proc_macro2::TokenStream, String> {
+    let Some(codec) = codec else {
+        return Ok(quote!(<io_plugin::codec::Cbor as io_plugin::Codec>::ID));
+    };
+    let codec = codec.to_string();
+    let ty = match codec.trim_matches('"') {
+        "cbor" => quote!(Cbor),
+        "json" => quote!(Json),
+        "msgpack" | "messagepack" => quote!(MessagePack),
+        "bincode" => quote!(Bincode),
+        other => {
+            return Err(format!(
+                "unknown codec `{other}` - expected one of \"cbor\", \"json\", \"msgpack\" or \"bincode\""
+            ))
+        }
+    };
+    Ok(quote!(<io_plugin::codec::#ty as io_plugin::Codec>::ID))
+}
+
+/// How a variant is called, as declared by its attributes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantKind {
+    /// A single request, answered with a single response
+    Unary,
+    /// `#[stream]` - a single request, answered with a stream of responses
+    Stream,
+    /// `#[client_stream]` - a request whose last argument is a stream of chunks, answered with a single response
+    ClientStream,
+    /// `#[notify]` - a one-way message, which the plugin doesn't respond to
+    Notify,
+}
+
+impl VariantKind {
+    /// The attributes which declare a variant's kind - at most one may be used on each variant
+    pub const ATTRIBUTES: [&'static str; 3] = ["stream", "client_stream", "notify"];
+
+    pub fn of(variant: &Variant) -> Self {
+        if has_attr(&variant.attrs, "stream") {
+            Self::Stream
+        } else if has_attr(&variant.attrs, "client_stream") {
+            Self::ClientStream
+        } else if has_attr(&variant.attrs, "notify") {
+            Self::Notify
+        } else {
+            Self::Unary
+        }
+    }
+}
+
+/// The type of a `#[client_stream]` variant's chunks - its last input field
+pub fn chunk_type(variant: &Variant) -> Option<Type> {
+    if VariantKind::of(variant) != VariantKind::ClientStream {
+        return None;
+    }
+    let fields = variant.fields.iter().collect::<Vec<_>>();
+    let [.., chunk, _] = fields[..] else {
+        return None;
+    };
+    Some(chunk.ty.to_owned())
+}
+
+/// The `#[timeout_ms(N)]` of a variant, if it has one
+pub fn timeout_ms(variant: &Variant) -> Result<Option<u64>, String> {
+    let Some((_, ms)) = list_attr_by_id(&variant.attrs, "timeout_ms") else {
+        return Ok(None);
+    };
+    match ms.to_string().parse::<u64>() {
+        Ok(ms) if ms > 0 => Ok(Some(ms)),
+        _ => Err("`timeout_ms` must be a positive number of milliseconds".to_string()),
+    }
+}
+
+/// The `#[error_type(E)]` of a variant, if it has one - the error its plugin method returns, which reaches the host as is
+pub fn error_type(variant: &Variant) -> Result<Option<Type>, String> {
+    let Some((_, ty)) = list_attr_by_id(&variant.attrs, "error_type") else {
+        return Ok(None);
+    };
+    syn::parse2::<Type>(ty)
+        .map(Some)
+        .map_err(|_| "`error_type` must be a type".to_string())
+}
+
+/// The error a variant's plugin method returns - its `#[error_type(E)]`, or [`io_plugin::RemoteError`]
+pub fn error_type_or_default(variant: &Variant) -> Type {
+    match error_type(variant) {
+        Ok(Some(ty)) => ty,
+        _ => parse_quote!(io_plugin::RemoteError),
+    }
+}
+
+pub fn has_attr(original: &[Attribute], id: &str) -> bool {
+    original
+        .iter()
+        .any(|a| a.path().get_ident().is_some_and(|ident| ident == id))
+}
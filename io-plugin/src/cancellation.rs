@@ -0,0 +1,69 @@
+use futures::future::{self, Either};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Notify;
+
+tokio::task_local! {
+    static CURRENT: CancellationToken;
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Cancelled when the host stops waiting for a request's response (e.g. because it dropped the call's future).
+///
+/// The plugin stops polling a cancelled request's method on its own - the token is for work that isn't polled by it,
+/// like spawned tasks or blocking loops, which can check [`CancellationToken::is_cancelled`]
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The token of the request the plugin is currently handling, when called from within one of its trait methods
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Self::clone).ok()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the token has been cancelled
+    pub async fn cancelled(&self) {
+        let mut notified = std::pin::pin!(self.inner.notify.notified());
+        // Registered before checking, so a cancellation in between isn't missed
+        notified.as_mut().enable();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await
+    }
+
+    /// Run `work` with this as the [`CancellationToken::current`] token - returning `None` if it's cancelled first
+    pub async fn run<F: Future>(&self, work: F) -> Option<F::Output> {
+        let cancelled = std::pin::pin!(self.cancelled());
+        let work = std::pin::pin!(CURRENT.scope(self.clone(), work));
+        match future::select(cancelled, work).await {
+            Either::Left(_) => None,
+            Either::Right((output, _)) => Some(output),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{error::Error, fmt::Display};
+
+use crate::IOPluginError;
+
+/// A serialisation format which messages can be sent in.
+///
+/// Each implementation is gated behind a cargo feature of the same name (`cbor` is enabled by default)
+pub trait Codec {
+    /// Identifies this codec in frame headers
+    const ID: CodecId;
+    type Error: Error + Send + Sync + 'static;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error>;
+}
+
+/// The codec a frame's payload has been serialised with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum CodecId {
+    Cbor = 1,
+    Json = 2,
+    MessagePack = 3,
+    Bincode = 4,
+}
+
+impl CodecId {
+    pub fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Cbor),
+            2 => Some(Self::Json),
+            3 => Some(Self::MessagePack),
+            4 => Some(Self::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Whether the cargo feature for this codec has been enabled
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::Cbor => cfg!(feature = "cbor"),
+            Self::Json => cfg!(feature = "json"),
+            Self::MessagePack => cfg!(feature = "msgpack"),
+            Self::Bincode => cfg!(feature = "bincode"),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        match self {
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Ok(Cbor::encode(value)?),
+            #[cfg(feature = "json")]
+            Self::Json => Ok(Json::encode(value)?),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => Ok(MessagePack::encode(value)?),
+            #[cfg(feature = "bincode")]
+            Self::Bincode => Ok(Bincode::encode(value)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(IOPluginError::UnsupportedCodec(self).into()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, Box<dyn Error + Send + Sync>> {
+        match self {
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Ok(Cbor::decode(bytes)?),
+            #[cfg(feature = "json")]
+            Self::Json => Ok(Json::decode(bytes)?),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => Ok(MessagePack::decode(bytes)?),
+            #[cfg(feature = "bincode")]
+            Self::Bincode => Ok(Bincode::decode(bytes)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(IOPluginError::UnsupportedCodec(self).into()),
+        }
+    }
+}
+
+impl Default for CodecId {
+    /// The first of the codecs whose feature is enabled, preferring CBOR
+    fn default() -> Self {
+        [Self::Cbor, Self::Json, Self::MessagePack, Self::Bincode]
+            .into_iter()
+            .find(|codec| codec.is_supported())
+            .unwrap_or(Self::Cbor)
+    }
+}
+
+impl Display for CodecId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Cbor => "cbor",
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Bincode => "bincode",
+        })
+    }
+}
+
+#[cfg(feature = "cbor")]
+/// [CBOR](https://cbor.io) - compact, and self-describing. The default
+pub struct Cbor;
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const ID: CodecId = CodecId::Cbor;
+    type Error = serde_cbor::Error;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        serde_cbor::to_vec(value)
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        serde_cbor::from_slice(bytes)
+    }
+}
+
+#[cfg(feature = "json")]
+/// Human-readable JSON - useful while debugging
+pub struct Json;
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const ID: CodecId = CodecId::Json;
+    type Error = serde_json::Error;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        serde_json::to_vec(value)
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+/// [MessagePack](https://msgpack.org), with struct fields encoded by name
+pub struct MessagePack;
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const ID: CodecId = CodecId::MessagePack;
+    type Error = MessagePackError;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Debug, thiserror::Error)]
+pub enum MessagePackError {
+    #[error(transparent)]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    Decode(#[from] rmp_serde::decode::Error),
+}
+
+#[cfg(feature = "bincode")]
+/// [bincode](https://docs.rs/bincode) - the most compact, but not self-describing
+/// (interface types must not rely on [`serde::Deserializer::deserialize_any`])
+pub struct Bincode;
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const ID: CodecId = CodecId::Bincode;
+    type Error = bincode::Error;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Self::Error> {
+        bincode::serialize(value)
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Self::Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
+/// Stands in for generic parameters which a particular message doesn't use
+#[cfg(feature = "cbor")]
+pub type GenericValue = serde_cbor::Value;
+/// Stands in for generic parameters which a particular message doesn't use
+#[cfg(all(not(feature = "cbor"), feature = "json"))]
+pub type GenericValue = serde_json::Value;
+/// Stands in for generic parameters which a particular message doesn't use
+#[cfg(not(any(feature = "cbor", feature = "json")))]
+pub type GenericValue = ();
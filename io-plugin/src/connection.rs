@@ -0,0 +1,643 @@
+use futures::{
+    future::{self, Either},
+    Stream, StreamExt,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch, Semaphore},
+    task::JoinHandle,
+    time::{Instant, Sleep},
+};
+
+use crate::{
+    host_handshake,
+    protocol::{read_frame_async, BoxedReader, BoxedWriter, Frame, FrameKind, FrameWriter},
+    logging::{self, LogRecord},
+    metrics::{self, Call, Metrics},
+    trace::{self, HostSpan, SpanReport},
+    CallError, CodecId, IOPluginError, PluginProcess, RemoteError, RetryPolicy, EXIT_GRACE,
+};
+
+/// How many chunks of a `#[client_stream]` argument (or items of a `#[stream]` variant's output)
+/// may be sent before the other side acknowledges any of them
+pub const CHUNK_WINDOW: usize = 8;
+
+/// Answers a plugin's request to the host's services (see `#[host_services]`) with a [`FrameKind::ServiceResponse`] frame
+pub type ServiceHandler =
+    Arc<dyn Fn(Frame) -> Pin<Box<dyn Future<Output = Result<Frame, IOPluginError>> + Send>> + Send + Sync>;
+
+/// A request which is still waiting for (some of) its response
+enum Waiting {
+    Call(oneshot::Sender<Frame>),
+    /// A `#[client_stream]` call - each chunk sent takes a credit, and each acknowledged chunk returns one
+    Upload(oneshot::Sender<Frame>, Arc<Semaphore>),
+    /// A `#[stream]` call - room for a window of items, plus whatever ends the stream
+    Stream(mpsc::Sender<Result<Frame, IOPluginError>>),
+}
+
+#[derive(Default)]
+struct Pending {
+    requests: HashMap<u64, Waiting>,
+    /// The spans requests were made in, until the plugin reports on them (with the `tracing` feature)
+    spans: HashMap<u64, HostSpan>,
+    /// Set once the reader has stopped - no further responses will arrive
+    closed: Option<IOPluginError>,
+}
+
+/// Cancels a request if it's dropped while the request is still waiting for (some of) its response -
+/// so the plugin can stop handling it, and its response is discarded if it arrives anyway
+struct CancelOnDrop {
+    id: u64,
+    codec: CodecId,
+    pending: Arc<std::sync::Mutex<Pending>>,
+    writer: Arc<FrameWriter>,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.requests.remove(&self.id).is_none() {
+            return;
+        }
+        // The plugin won't report on a cancelled request
+        pending.spans.remove(&self.id);
+        drop(pending);
+        if let Ok(cancel) = Frame::encode(FrameKind::Cancel, self.id, self.codec, &()) {
+            let _ = self.writer.queue(&cancel);
+        }
+    }
+}
+
+/// The host's end of the pipes to a plugin.
+///
+/// Requests can be made concurrently through a shared reference - each is tagged with a request ID,
+/// and a background task dispatches responses to whichever call is waiting for that ID.
+/// Dropping a call's future before its response arrives cancels the request, so it's never mistaken for another's
+pub struct Connection {
+    writer: Arc<FrameWriter>,
+    pending: Arc<std::sync::Mutex<Pending>>,
+    next_id: AtomicU64,
+    codec: CodecId,
+    /// How long to wait for the plugin, unless overridden
+    timeout: Option<Duration>,
+    /// How calls to idempotent variants are retried, if at all
+    retry: Option<RetryPolicy>,
+    /// What the plugin's log records are labelled with - see [`Connection::set_plugin`]
+    plugin: Arc<std::sync::Mutex<String>>,
+    metrics: Arc<Metrics>,
+    /// Becomes `true` once the reader has stopped
+    closed: watch::Receiver<bool>,
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    /// Handshake with the plugin, then start dispatching its responses - and its requests to `services`, if the host provides any.
+    /// If the plugin's `process` is known, calls failing because it exited report how it did.
+    /// Must be called within a tokio runtime
+    pub async fn connect(
+        mut reader: BoxedReader,
+        mut writer: BoxedWriter,
+        codec: CodecId,
+        fingerprint: u64,
+        services: Option<ServiceHandler>,
+        process: Option<PluginProcess>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if let Err(err) = host_handshake(writer.as_mut(), reader.as_mut(), codec, fingerprint).await {
+            return Err(Self::exited_error(err, process.as_ref()).await);
+        }
+        let pending = Arc::new(std::sync::Mutex::new(Pending::default()));
+        let writer = Arc::new(FrameWriter::new(writer));
+        let plugin = Arc::new(std::sync::Mutex::new(String::new()));
+        let (closing, closed) = watch::channel(false);
+        let reader = tokio::spawn(Self::dispatch_responses(
+            reader,
+            pending.clone(),
+            writer.clone(),
+            services,
+            plugin.clone(),
+            process,
+            closing,
+        ));
+        Ok(Self {
+            writer,
+            pending,
+            next_id: AtomicU64::new(1),
+            codec,
+            timeout: None,
+            retry: None,
+            plugin,
+            metrics: Default::default(),
+            closed,
+            reader,
+        })
+    }
+
+    /// If the handshake failed because the pipes closed, it's generally because the plugin exited (e.g. because it couldn't start) -
+    /// if it has, the error says how
+    async fn exited_error(err: Box<dyn Error + Send + Sync>, process: Option<&PluginProcess>) -> Box<dyn Error + Send + Sync> {
+        let closed = matches!(err.downcast_ref(), Some(IOPluginError::PipeClosed)) || err.is::<std::io::Error>();
+        let Some(process) = process.filter(|_| closed) else {
+            return err;
+        };
+        match tokio::time::timeout(EXIT_GRACE, process.wait()).await {
+            Ok(Ok(exit)) => IOPluginError::Exited(exit).into(),
+            _ => err,
+        }
+    }
+
+    pub fn codec(&self) -> CodecId {
+        self.codec
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Limit how long calls wait for the plugin, unless the variant called has its own `#[timeout_ms(..)]`
+    /// or the call is made within [`with_timeout`]. A call which times out is cancelled
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry
+    }
+
+    /// Retry calls to `#[idempotent]` variants as `retry` says - see [`Connection::retrying`]
+    pub fn set_retry_policy(&mut self, retry: Option<RetryPolicy>) {
+        self.retry = retry;
+    }
+
+    /// Make `attempt` (a call to an idempotent variant) - then again, as long as the [`RetryPolicy`] says to.
+    /// Each attempt has its own timeout
+    pub async fn retrying<T, E, F: Future<Output = Result<T, CallError<E>>>>(
+        &self,
+        mut attempt: impl FnMut() -> F,
+    ) -> Result<T, CallError<E>> {
+        let Some(policy) = self.retry else {
+            return attempt().await;
+        };
+        let mut attempts = 1;
+        loop {
+            match attempt().await {
+                Err(err) if attempts < policy.max_attempts && policy.retries(&err) => {
+                    tokio::time::sleep(policy.delay(attempts)).await;
+                    attempts += 1;
+                    if let Some(call) = Call::current() {
+                        call.retried();
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether the pipes have closed (or failed) - generally because the plugin exited.
+    /// Every call made from then on fails
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Completes once the pipes have closed - see [`Connection::is_closed`]
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed.clone();
+        async move {
+            // An error means the reader has been dropped along with the connection - so it's closed either way
+            let _ = closed.wait_for(|closed| *closed).await;
+        }
+    }
+
+    /// Take over `previous`'s settings, label and metrics - for a connection replacing it (e.g. to a respawned plugin)
+    pub fn inherit(&mut self, previous: &Connection) {
+        self.timeout = previous.timeout;
+        self.retry = previous.retry;
+        self.set_plugin(previous.plugin.lock().unwrap().as_str());
+        self.metrics = previous.metrics.clone();
+    }
+
+    /// Label the plugin's log records (see [`crate::PluginLogger`]) with `plugin` - e.g. its name, or its path until that's known
+    pub fn set_plugin(&self, plugin: impl ToString) {
+        *self.plugin.lock().unwrap() = plugin.to_string();
+    }
+
+    /// Make `call` to the plugin's `method` (e.g. through [`Connection::call`]), recording it in [`Connection::metrics`] -
+    /// and through the `metrics` crate, with the `metrics` feature
+    pub async fn track<T, E>(
+        &self,
+        method: &'static str,
+        call: impl Future<Output = Result<T, CallError<E>>>,
+    ) -> Result<T, CallError<E>> {
+        self.metrics.track(&self.plugin, method, call).await
+    }
+
+    /// What's been recorded about calls made through [`Connection::track`], by method
+    pub fn metrics(&self) -> HashMap<&'static str, crate::MethodMetrics> {
+        self.metrics.snapshot()
+    }
+
+    async fn dispatch_responses(
+        mut reader: BoxedReader,
+        pending: Arc<std::sync::Mutex<Pending>>,
+        writer: Arc<FrameWriter>,
+        services: Option<ServiceHandler>,
+        plugin: Arc<std::sync::Mutex<String>>,
+        process: Option<PluginProcess>,
+        closing: watch::Sender<bool>,
+    ) {
+        let err = loop {
+            let frame = match read_frame_async(reader.as_mut()).await {
+                Ok(frame) => frame,
+                // Its payload has been skipped, so the next frame starts where it's expected to
+                Err(err) if matches!(err.downcast_ref(), Some(IOPluginError::UnknownFrameKind(_))) => continue,
+                Err(err) => break err,
+            };
+            // Only locked for as long as it takes to find who's waiting - log records and span reports are emitted without it,
+            // since whatever they're emitted to may block (or call back into the handle)
+            match frame.kind {
+                FrameKind::Response | FrameKind::Panic => {
+                    let waiting = pending.lock().unwrap().requests.remove(&frame.id);
+                    match waiting {
+                        Some(Waiting::Call(call) | Waiting::Upload(call, _)) => {
+                            let _ = call.send(frame);
+                        }
+                        // The plugin couldn't start (or finish) the stream - its error is the last item
+                        Some(Waiting::Stream(stream)) => {
+                            let _ = stream.try_send(Ok(frame));
+                        }
+                        None => {}
+                    }
+                }
+                FrameKind::StreamItem => {
+                    let id = frame.id;
+                    let mut pending = pending.lock().unwrap();
+                    if let Some(Waiting::Stream(stream)) = pending.requests.get(&id) {
+                        // The last slot is kept for whatever ends the stream
+                        let sent = if stream.capacity() > 1 {
+                            stream.try_send(Ok(frame)).is_ok()
+                        } else {
+                            let _ = stream.try_send(Err(IOPluginError::InvalidFrame(
+                                "The plugin sent more stream items than the host acknowledged".to_string(),
+                            )));
+                            false
+                        };
+                        if !sent {
+                            // The stream has been dropped on the host's side, or the plugin broke the window
+                            pending.requests.remove(&id);
+                        }
+                    }
+                }
+                FrameKind::StreamEnd => {
+                    pending.lock().unwrap().requests.remove(&frame.id);
+                }
+                FrameKind::ChunkAck => {
+                    if let Some(Waiting::Upload(_, credits)) = pending.lock().unwrap().requests.get(&frame.id) {
+                        credits.add_permits(1);
+                    }
+                }
+                FrameKind::ServiceRequest => {
+                    tokio::spawn(Self::serve(frame, services.clone(), writer.clone()));
+                }
+                FrameKind::Log => {
+                    if let Ok(record) = frame.decode::<LogRecord>() {
+                        let request = (frame.id != 0).then_some(frame.id);
+                        let plugin = plugin.lock().unwrap().clone();
+                        logging::emit(&plugin, request, record);
+                    }
+                }
+                FrameKind::SpanReport => {
+                    let span = pending.lock().unwrap().spans.remove(&frame.id);
+                    if let (Some(span), Ok(report)) = (span, frame.decode::<SpanReport>()) {
+                        let plugin = plugin.lock().unwrap().clone();
+                        trace::report(span, &plugin, frame.id, report);
+                    }
+                }
+                FrameKind::Handshake
+                | FrameKind::Request
+                | FrameKind::Chunk
+                | FrameKind::ChunkEnd
+                | FrameKind::ServiceResponse
+                | FrameKind::Notification
+                | FrameKind::Cancel
+                | FrameKind::SpanContext
+                | FrameKind::Shutdown => {}
+            }
+        };
+        let err = match err.downcast::<IOPluginError>() {
+            Ok(err) => *err,
+            Err(err) => IOPluginError::Other(err.to_string()),
+        };
+        // The pipes generally close because the plugin exited - if it has, the error says how
+        let err = match (err, process) {
+            (IOPluginError::PipeClosed, Some(process)) => match tokio::time::timeout(EXIT_GRACE, process.wait()).await {
+                Ok(Ok(exit)) => IOPluginError::Exited(exit),
+                _ => IOPluginError::PipeClosed,
+            },
+            (err, _) => err,
+        };
+        let mut pending = pending.lock().unwrap();
+        // Dropping the senders wakes every waiting call and stream
+        for (_, waiting) in pending.requests.drain() {
+            if let Waiting::Stream(stream) = waiting {
+                let _ = stream.try_send(Err(err.clone()));
+            }
+        }
+        pending.spans.clear();
+        pending.closed = Some(err);
+        closing.send_replace(true);
+    }
+
+    /// Respond to a plugin's request to the host's services
+    async fn serve(request: Frame, services: Option<ServiceHandler>, writer: Arc<FrameWriter>) {
+        let (id, codec) = (request.id, request.codec);
+        let response = match services {
+            Some(services) => services(request).await,
+            None => Err(IOPluginError::Other(
+                "The host doesn't provide any services".to_string(),
+            )),
+        };
+        let response = response.or_else(|err| {
+            Frame::encode(FrameKind::ServiceResponse, id, codec, &Err::<(), _>(RemoteError::from(err)))
+                .map_err(|err| IOPluginError::Other(err.to_string()))
+        });
+        if let Ok(response) = response {
+            let _ = writer.write(&response).await;
+        }
+    }
+
+    /// Write `frame` to the plugin. If that fails because the pipes have closed (e.g. because the plugin exited),
+    /// the error says so - once the reader has noticed too
+    async fn write<E>(&self, frame: &Frame) -> Result<(), CallError<E>> {
+        let Err(err) = self.writer.write(frame).await else {
+            return Ok(());
+        };
+        let err = CallError::transport(err);
+        let _ = tokio::time::timeout(EXIT_GRACE, self.closed()).await;
+        match &self.pending.lock().unwrap().closed {
+            Some(closed) => Err(closed.clone().into()),
+            None => Err(err),
+        }
+    }
+
+    fn closed_error<E>(&self) -> CallError<E> {
+        self.pending
+            .lock()
+            .unwrap()
+            .closed
+            .clone()
+            .unwrap_or(IOPluginError::PipeClosed)
+            .into()
+    }
+
+    /// How long to wait for the plugin - the [`with_timeout`] in effect, if any, then the variant's timeout, then the connection's
+    fn limit(&self, timeout: Option<Duration>) -> Option<Duration> {
+        CALL_TIMEOUT.try_with(|timeout| *timeout).ok().or(timeout).or(self.timeout)
+    }
+
+    /// Register a request as waiting for a response, then send it - the returned guard cancels it if dropped.
+    /// If it's made within a `tracing` span (with the `tracing` feature), the span's context is sent ahead of it
+    async fn send_request<T: Serialize, E>(&self, message: &T, waiting: Waiting) -> Result<CancelOnDrop, CallError<E>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Frame::encode(FrameKind::Request, id, self.codec, message).map_err(CallError::decode)?;
+        let span = trace::current_span();
+        let context = match &span {
+            Some((_, context)) => Some(Frame::encode(FrameKind::SpanContext, id, self.codec, context).map_err(CallError::decode)?),
+            None => None,
+        };
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(err) = &pending.closed {
+                return Err(err.clone().into());
+            }
+            pending.requests.insert(id, waiting);
+            if let Some((span, _)) = span {
+                pending.spans.insert(id, span);
+            }
+        }
+        let request = CancelOnDrop {
+            id,
+            codec: self.codec,
+            pending: self.pending.clone(),
+            writer: self.writer.clone(),
+        };
+        if let Some(context) = context {
+            self.write(&context).await?;
+            metrics::sent(&context);
+        }
+        self.write(&frame).await?;
+        metrics::sent(&frame);
+        Ok(request)
+    }
+
+    /// Send `message` as a request, and wait for the plugin's response to it.
+    /// `timeout` is the variant's timeout, if it has one, and `E` is its error type
+    pub async fn call<T: Serialize, E>(&self, message: &T, timeout: Option<Duration>) -> Result<Frame, CallError<E>> {
+        within(self.limit(timeout), async {
+            let (sender, receiver) = oneshot::channel();
+            let _request = self.send_request(message, Waiting::Call(sender)).await?;
+            let response = receiver.await.map_err(|_| self.closed_error())?;
+            metrics::received(&response);
+            Ok(response)
+        })
+        .await
+    }
+
+    /// Send `message` as a request to a `#[stream]` variant - the plugin's responses arrive through the returned stream.
+    /// `timeout` (the variant's timeout, if it has one) applies to each response.
+    ///
+    /// The plugin sends at most [`CHUNK_WINDOW`] items ahead of what's been consumed from the stream,
+    /// so a slow consumer slows down the plugin rather than buffering its output
+    pub async fn stream<T: Serialize, E>(&self, message: &T, timeout: Option<Duration>) -> Result<ResponseStream<E>, CallError<E>> {
+        let limit = self.limit(timeout);
+        let (sender, receiver) = mpsc::channel(CHUNK_WINDOW + 1);
+        let request = within(limit, self.send_request(message, Waiting::Stream(sender))).await?;
+        Ok(ResponseStream {
+            receiver,
+            request: Some(request),
+            deadline: limit.map(|limit| (limit, Box::pin(tokio::time::sleep(limit)))),
+            call: Call::current(),
+            _error: PhantomData,
+        })
+    }
+
+    /// Ask the plugin to exit, once it's finished handling the requests already sent - without waiting for it to.
+    /// Requests sent from then on are ignored
+    pub async fn shutdown(&self) -> Result<(), CallError> {
+        let frame = Frame::encode(FrameKind::Shutdown, 0, self.codec, &()).map_err(CallError::decode)?;
+        {
+            if let Some(err) = &self.pending.lock().unwrap().closed {
+                return Err(err.clone().into());
+            }
+        }
+        self.write(&frame).await
+    }
+
+    /// Send `message` to a `#[notify]` variant - returning once it's been written, without waiting for the plugin to handle it
+    pub async fn notify<T: Serialize, E>(&self, message: &T, timeout: Option<Duration>) -> Result<(), CallError<E>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Frame::encode(FrameKind::Notification, id, self.codec, message).map_err(CallError::decode)?;
+        {
+            if let Some(err) = &self.pending.lock().unwrap().closed {
+                return Err(err.clone().into());
+            }
+        }
+        within(self.limit(timeout), async {
+            self.write(&frame).await
+        })
+        .await?;
+        metrics::sent(&frame);
+        Ok(())
+    }
+
+    /// Send `message` as a request to a `#[client_stream]` variant, followed by `chunks` - then wait for the plugin's response.
+    ///
+    /// At most [`CHUNK_WINDOW`] chunks are sent ahead of what the plugin has consumed, so a slow plugin slows down
+    /// the upload rather than buffering all of it. If the plugin responds early (e.g. with an error),
+    /// the remaining chunks aren't sent.
+    ///
+    /// `timeout` (the variant's timeout, if it has one) applies to each wait for the plugin - to consume a chunk, or to respond
+    pub async fn upload<T: Serialize, C: Serialize, E>(
+        &self,
+        message: &T,
+        chunks: impl Stream<Item = C>,
+        timeout: Option<Duration>,
+    ) -> Result<Frame, CallError<E>> {
+        let limit = self.limit(timeout);
+        let (sender, receiver) = oneshot::channel();
+        let credits = Arc::new(Semaphore::new(CHUNK_WINDOW));
+        let request = within(
+            limit,
+            self.send_request(message, Waiting::Upload(sender, credits.clone())),
+        )
+        .await?;
+        let id = request.id;
+        let send_chunks = async {
+            let mut chunks = std::pin::pin!(chunks);
+            while let Some(chunk) = chunks.next().await {
+                within(limit, async {
+                    credits.acquire().await.map_err(|_| self.closed_error())?.forget();
+                    Ok(())
+                })
+                .await?;
+                let chunk = Frame::encode(FrameKind::Chunk, id, self.codec, &chunk).map_err(CallError::decode)?;
+                within(limit, async { self.write(&chunk).await }).await?;
+                metrics::sent(&chunk);
+            }
+            let end = Frame::encode(FrameKind::ChunkEnd, id, self.codec, &()).map_err(CallError::decode)?;
+            within(limit, async { self.write(&end).await }).await?;
+            metrics::sent(&end);
+            Ok(())
+        };
+        let response = match future::select(std::pin::pin!(send_chunks), receiver).await {
+            Either::Left((Ok(()), receiver)) => {
+                within(limit, async { receiver.await.map_err(|_| self.closed_error()) }).await
+            }
+            Either::Left((Err(err), _)) => Err(err),
+            Either::Right((response, _)) => response.map_err(|_| self.closed_error()),
+        };
+        drop(request);
+        if let Ok(response) = &response {
+            metrics::received(response);
+        }
+        response
+    }
+}
+
+tokio::task_local! {
+    static CALL_TIMEOUT: Duration;
+}
+
+/// Run `calls`, with every call to a plugin made within it limited to `timeout` -
+/// overriding the timeouts of the variants called, and of the handles they're called through
+pub async fn with_timeout<F: Future>(timeout: Duration, calls: F) -> F::Output {
+    CALL_TIMEOUT.scope(timeout, calls).await
+}
+
+/// Wait for `work` - for no longer than `limit`, if there is one
+async fn within<T, E>(
+    limit: Option<Duration>,
+    work: impl Future<Output = Result<T, CallError<E>>>,
+) -> Result<T, CallError<E>> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, work)
+            .await
+            .map_err(|_| CallError::Timeout(limit))?,
+        None => work.await,
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// The frames a plugin sends in response to a `#[stream]` variant.
+/// Ends with a [`CallError`] if the connection closes before the plugin ends the stream,
+/// or if the plugin takes longer than the timeout to send a response.
+/// Dropping it before then cancels the request
+pub struct ResponseStream<E = RemoteError> {
+    receiver: mpsc::Receiver<Result<Frame, IOPluginError>>,
+    /// Dropped (cancelling the request) once the stream times out
+    request: Option<CancelOnDrop>,
+    deadline: Option<(Duration, Pin<Box<Sleep>>)>,
+    /// The call which opened the stream, if it's being tracked - its responses are counted towards it
+    call: Option<Call>,
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<E> Stream for ResponseStream<E> {
+    type Item = Result<Frame, CallError<E>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.request.is_none() {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(response) = self.receiver.poll_recv(cx) {
+            if let Some((limit, deadline)) = &mut self.deadline {
+                deadline.as_mut().reset(Instant::now() + *limit);
+            }
+            // Let the plugin send another item in place of this one
+            if let (Some(Ok(frame)), Some(request)) = (&response, &self.request) {
+                if frame.kind == FrameKind::StreamItem {
+                    if let Ok(ack) = Frame::encode(FrameKind::ChunkAck, request.id, request.codec, &()) {
+                        let _ = request.writer.queue(&ack);
+                    }
+                }
+            }
+            let response = response.map(|response| response.map_err(CallError::from));
+            if let Some(call) = &self.call {
+                match &response {
+                    Some(Ok(frame)) => call.received(frame.size()),
+                    Some(Err(err)) => call.failed(err.kind()),
+                    None => {}
+                }
+            }
+            return Poll::Ready(response);
+        }
+        let Some((limit, deadline)) = &mut self.deadline else {
+            return Poll::Pending;
+        };
+        if deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let limit = *limit;
+        self.request = None;
+        if let Some(call) = &self.call {
+            call.failed("timeout");
+        }
+        Poll::Ready(Some(Err(CallError::Timeout(limit))))
+    }
+}
@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    error::Error,
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+use crate::{IOPluginError, PluginExit};
+
+/// An error returned by the other end of the connection (e.g. by a plugin's method), as sent through the pipes.
+///
+/// Anything which converts into a `Box<dyn Error + Send + Sync>` converts into this - so `?` works on any error within a plugin's methods.
+/// That's also why it doesn't implement [`std::error::Error`] itself - a [`CallError::Remote`] holding it does,
+/// and its `source()` walks the chain of errors which caused it on the other end
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub message: String,
+    /// What kind of error it was, if known - the [`std::io::ErrorKind`] of an IO error, or whatever was set with [`RemoteError::with_kind`]
+    pub kind: Option<String>,
+    /// The method which returned it
+    pub method: Option<String>,
+    /// What caused it, as reported by `source()` on the other end
+    pub cause: Option<Box<RemoteCause>>,
+    /// Where it was created - only captured when backtraces are enabled on the other end (e.g. with `RUST_BACKTRACE=1`)
+    pub backtrace: Option<String>,
+}
+
+impl RemoteError {
+    pub fn new(message: impl Display) -> Self {
+        Self {
+            message: message.to_string(),
+            kind: None,
+            method: None,
+            cause: None,
+            backtrace: capture_backtrace(),
+        }
+    }
+
+    pub fn with_kind(mut self, kind: impl Display) -> Self {
+        self.kind = Some(kind.to_string());
+        self
+    }
+
+    /// Record that the error was returned by `method` - unless it's already been recorded as returned by another
+    pub fn in_method(mut self, method: &str) -> Self {
+        self.method.get_or_insert_with(|| method.to_string());
+        self
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl<E: Into<Box<dyn Error + Send + Sync>>> From<E> for RemoteError {
+    fn from(err: E) -> Self {
+        let err = err.into();
+        Self {
+            message: err.to_string(),
+            kind: err
+                .downcast_ref::<std::io::Error>()
+                .map(|err| format!("{:?}", err.kind())),
+            method: None,
+            cause: RemoteCause::chain(err.source()),
+            backtrace: capture_backtrace(),
+        }
+    }
+}
+
+fn capture_backtrace() -> Option<String> {
+    let backtrace = Backtrace::capture();
+    (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string())
+}
+
+/// One of the errors which caused a [`RemoteError`], as reconstructed on this end of the connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteCause {
+    pub message: String,
+    pub cause: Option<Box<RemoteCause>>,
+}
+
+impl RemoteCause {
+    fn chain(source: Option<&(dyn Error + 'static)>) -> Option<Box<Self>> {
+        let source = source?;
+        Some(Box::new(Self {
+            message: source.to_string(),
+            cause: Self::chain(source.source()),
+        }))
+    }
+}
+
+impl Display for RemoteCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for RemoteCause {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_deref().map(|cause| cause as _)
+    }
+}
+
+/// What caused an error returned by a method - implemented for [`RemoteError`],
+/// and for any [`std::error::Error`] (as declared with `#[error_type(E)]`)
+pub trait RemoteSource {
+    fn remote_source(&self) -> Option<&(dyn Error + 'static)>;
+}
+
+impl<E: Error> RemoteSource for E {
+    fn remote_source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source()
+    }
+}
+
+impl RemoteSource for RemoteError {
+    fn remote_source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_deref().map(|cause| cause as _)
+    }
+}
+
+/// Why a call through a handle (or to the host's services) failed.
+///
+/// `E` is the error the method called returns - [`RemoteError`], unless its variant is marked `#[error_type(E)]`
+#[derive(Debug, Clone)]
+pub enum CallError<E = RemoteError> {
+    /// The pipes couldn't be written to or read from
+    Transport(IOPluginError),
+    /// A message couldn't be encoded, or a response couldn't be decoded
+    Decode(String),
+    Timeout(Duration),
+    /// The other end closed the pipes - generally because its process exited.
+    /// Carries how the plugin exited, if its process is known (i.e. it was spawned by the handle) and it exited shortly after
+    Crashed(Option<PluginExit>),
+    /// The method called panicked
+    Panicked { message: String, location: Option<String> },
+    /// The call was handled, and returned an error
+    Remote(E),
+}
+
+impl<E: Display> Display for CallError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(err) => write!(f, "Couldn't communicate with the plugin: {err}"),
+            Self::Decode(err) => write!(f, "Couldn't decode the response: {err}"),
+            Self::Timeout(limit) => write!(f, "Plugin didn't respond within {limit:?}"),
+            Self::Crashed(None) => f.write_str("Plugin has exited"),
+            Self::Crashed(Some(exit)) => write!(f, "Plugin {exit}"),
+            Self::Panicked { message, location: Some(location) } => write!(f, "Plugin panicked at {location}: {message}"),
+            Self::Panicked { message, location: None } => write!(f, "Plugin panicked: {message}"),
+            Self::Remote(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl<E: Debug + Display + RemoteSource> Error for CallError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Transport(err) => Some(err),
+            Self::Remote(err) => err.remote_source(),
+            _ => None,
+        }
+    }
+}
+
+impl<E> From<IOPluginError> for CallError<E> {
+    fn from(err: IOPluginError) -> Self {
+        match err {
+            IOPluginError::PipeClosed => Self::Crashed(None),
+            IOPluginError::Exited(exit) => Self::Crashed(Some(exit)),
+            err => Self::Transport(err),
+        }
+    }
+}
+
+impl<E> CallError<E> {
+    /// Classify an error from writing or reading frames
+    pub fn transport(err: Box<dyn Error + Send + Sync>) -> Self {
+        let err = match err.downcast::<IOPluginError>() {
+            Ok(err) => return (*err).into(),
+            Err(err) => err,
+        };
+        match err.downcast::<std::io::Error>() {
+            Ok(err) => Self::Transport(IOPluginError::Io {
+                kind: format!("{:?}", err.kind()),
+                message: err.to_string(),
+            }),
+            Err(err) => Self::Transport(IOPluginError::Other(err.to_string())),
+        }
+    }
+
+    pub fn decode(err: impl Display) -> Self {
+        Self::Decode(err.to_string())
+    }
+
+    /// Which variant this is, in snake case - e.g. for labelling metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Transport(_) => "transport",
+            Self::Decode(_) => "decode",
+            Self::Timeout(_) => "timeout",
+            Self::Crashed(_) => "crashed",
+            Self::Panicked { .. } => "panicked",
+            Self::Remote(_) => "remote",
+        }
+    }
+}
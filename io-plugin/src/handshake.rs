@@ -0,0 +1,138 @@
+use std::{error::Error, pin::Pin};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    protocol::{map_read_error, parse_header, Frame, FrameKind, HEADER_SIZE, PROTOCOL_VERSION},
+    CodecId, IOPluginError,
+};
+
+/// The first frame exchanged in each direction, before any messages.
+///
+/// Its payload has a fixed layout (protocol version, codec, then the fingerprint as a big-endian `u64`)
+/// rather than being serialised with a codec - so both sides can always read it, even when they disagree on the rest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: u8,
+    /// The codec the host would like to use, or the one the plugin has agreed to
+    pub codec: CodecId,
+    /// Computed by [`crate::io_plugin`] from the interface's message and response enums
+    pub fingerprint: u64,
+}
+
+impl Handshake {
+    const SIZE: usize = 10;
+
+    pub fn new(codec: CodecId, fingerprint: u64) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            codec,
+            fingerprint,
+        }
+    }
+
+    fn to_frame(self) -> Frame {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.push(self.protocol_version);
+        payload.push(self.codec as u8);
+        payload.extend(self.fingerprint.to_be_bytes());
+        Frame {
+            kind: FrameKind::Handshake,
+            id: 0,
+            codec: self.codec,
+            payload,
+        }
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, IOPluginError> {
+        let invalid = || IOPluginError::InvalidFrame("malformed handshake".to_string());
+        let [protocol_version, codec, fingerprint @ ..] = payload else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            protocol_version: *protocol_version,
+            codec: CodecId::from_u8(*codec).ok_or_else(invalid)?,
+            fingerprint: u64::from_be_bytes(fingerprint.try_into().map_err(|_| invalid())?),
+        })
+    }
+}
+
+/// Read a handshake frame. Only the magic byte is checked, since the protocol version is what's being negotiated
+async fn read_handshake(mut source: Pin<&mut (dyn AsyncRead + Send)>) -> Result<Handshake, Box<dyn Error + Send + Sync>> {
+    let mut header = [0; HEADER_SIZE];
+    source.read_exact(&mut header).await.map_err(map_read_error)?;
+    let header = parse_header(&header)?;
+    if header.kind != FrameKind::Handshake as u8 || header.len != Handshake::SIZE {
+        return Err(IOPluginError::InvalidFrame("malformed handshake".to_string()).into());
+    }
+    let mut payload = [0; Handshake::SIZE];
+    source.read_exact(&mut payload).await.map_err(map_read_error)?;
+    Ok(Handshake::from_payload(&payload)?)
+}
+
+async fn write_handshake<Write: AsyncWrite + Send + ?Sized>(
+    mut sink: Pin<&mut Write>,
+    handshake: Handshake,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    sink.write_all(&handshake.to_frame().to_bytes()?).await?;
+    sink.flush().await?;
+    Ok(())
+}
+
+/// Host side of the handshake - propose `codec`, and check the plugin's protocol version and interface fingerprint
+pub async fn host_handshake<Write: AsyncWrite + Send + ?Sized>(
+    sink: Pin<&mut Write>,
+    source: Pin<&mut (dyn AsyncRead + Send)>,
+    codec: CodecId,
+    fingerprint: u64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_handshake(sink, Handshake::new(codec, fingerprint)).await?;
+    let reply = read_handshake(source).await?;
+    if reply.protocol_version != PROTOCOL_VERSION {
+        Err(IOPluginError::IncompatibleProtocol {
+            expected: PROTOCOL_VERSION,
+            found: reply.protocol_version,
+        })?;
+    }
+    if reply.fingerprint != fingerprint {
+        Err(IOPluginError::IncompatibleInterface {
+            expected: fingerprint,
+            found: reply.fingerprint,
+        })?;
+    }
+    if reply.codec != codec {
+        Err(IOPluginError::UnsupportedCodec(codec))?;
+    }
+    Ok(())
+}
+
+/// Plugin side of the handshake - always replies (so the host can report what went wrong),
+/// then fails if the host is incompatible. Returns the codec agreed upon
+pub async fn plugin_handshake<Write: AsyncWrite + Send + ?Sized>(
+    source: Pin<&mut (dyn AsyncRead + Send)>,
+    sink: Pin<&mut Write>,
+    fingerprint: u64,
+) -> Result<CodecId, Box<dyn Error + Send + Sync>> {
+    let request = read_handshake(source).await?;
+    let codec = if request.codec.is_supported() {
+        request.codec
+    } else {
+        CodecId::default()
+    };
+    write_handshake(sink, Handshake::new(codec, fingerprint)).await?;
+    if request.protocol_version != PROTOCOL_VERSION {
+        Err(IOPluginError::IncompatibleProtocol {
+            expected: PROTOCOL_VERSION,
+            found: request.protocol_version,
+        })?;
+    }
+    if request.fingerprint != fingerprint {
+        Err(IOPluginError::IncompatibleInterface {
+            expected: fingerprint,
+            found: request.fingerprint,
+        })?;
+    }
+    if codec != request.codec {
+        Err(IOPluginError::UnsupportedCodec(request.codec))?;
+    }
+    Ok(codec)
+}
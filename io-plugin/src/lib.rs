@@ -1,35 +1,87 @@
 #![feature(trait_alias)]
+pub mod codec;
+mod cancellation;
+mod connection;
+mod error;
+mod handshake;
+mod logging;
+mod metrics;
+mod panic;
 mod protocol;
+mod retry;
+mod server;
+mod stderr;
+mod supervisor;
+mod trace;
 mod tokio_exports;
 mod process;
 
 pub use io_plugin_macros::*;
 pub use tokio_exports::*;
 pub use process::*;
-pub use protocol::{io_read, io_read_async, io_write, io_write_async};
+pub use panic::PanicPolicy;
+pub use retry::RetryPolicy;
+pub use logging::PluginLogger;
+pub use metrics::{Latency, MethodMetrics, LATENCY_BUCKETS};
+pub use cancellation::CancellationToken;
+pub use codec::{Codec, CodecId, GenericValue};
+pub use handshake::{host_handshake, plugin_handshake, Handshake};
+pub use connection::{with_timeout, Connection, ResponseStream, ServiceHandler, CHUNK_WINDOW};
+pub use error::{CallError, RemoteCause, RemoteError, RemoteSource};
+pub use futures::{Stream, StreamExt};
+pub use log;
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing;
+#[doc(hidden)]
+pub use trace::in_method_span;
+pub use protocol::{
+    read_frame, read_frame_async, write_frame, write_frame_async, BoxedReader, BoxedWriter, Frame,
+    FrameKind, FrameWriter, MAX_FRAME_SIZE,
+};
+pub use server::{serve_concurrently, HostConnection, RequestStream, Requests};
+pub use stderr::{StderrLog, StderrMode, STDERR_HISTORY, STDERR_LINE_LIMIT};
+pub use supervisor::{RestartPolicy, Respawn, Supervisor, SupervisorState};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::process::{ChildStdin, ChildStdout};
 
-#[derive(Debug, Serialize, Deserialize, Error)]
+#[derive(Debug, Clone, Serialize, Deserialize, Error)]
 pub enum IOPluginError {
     #[error("Pipe has been closed")]
     PipeClosed,
+    #[error("Plugin {0}")]
+    Exited(PluginExit),
     #[error("Plugin failed to initialise: {0}")]
     InitialisationError(String),
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+    /// A frame of a kind this version of io-plugin doesn't know - its payload has been skipped,
+    /// so the frames after it can still be read
+    #[error("Skipped a frame of unknown kind {0}")]
+    UnknownFrameKind(u8),
+    #[error("Unsupported io-plugin protocol version {0}")]
+    UnsupportedProtocolVersion(u8),
+    /// A frame's payload is larger than [`MAX_FRAME_SIZE`]
+    #[error("Message of {0} bytes is too large to fit in a frame")]
+    FrameTooLarge(usize),
+    #[error("The {0} codec is not enabled - enable the `{0}` feature of io-plugin")]
+    UnsupportedCodec(CodecId),
+    #[error("Plugin speaks io-plugin protocol version {found}, but version {expected} was expected")]
+    IncompatibleProtocol { expected: u8, found: u8 },
+    #[error("Plugin was built against an incompatible interface (fingerprint {found:#018x}, expected {expected:#018x})")]
+    IncompatibleInterface { expected: u64, found: u64 },
+    #[error("Plugin needed more than {restarts} restarts within {window:?}, so it's been given up on")]
+    GaveUp { restarts: u32, window: std::time::Duration },
+    /// An IO error on the pipes - `kind` is the name of its [`std::io::ErrorKind`]
+    #[error("{message}")]
+    Io { kind: String, message: String },
     #[error("{0}")]
     Other(String),
 }
 
-pub struct ChildStdio {
-    pub stdin: ChildStdin,
-    pub stdout: ChildStdout,
-}
-
 pub type Mutex<T> = tokio::sync::Mutex<T>;
 pub type Child = tokio::process::Child;
+pub use tokio::io::{AsyncRead, AsyncWrite};
 
 pub trait Serialise = serde::Serialize;
 pub trait Deserialise = serde::de::DeserializeOwned;
-
-pub type GenericValue = serde_cbor::Value;
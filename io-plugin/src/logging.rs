@@ -0,0 +1,131 @@
+use log::{
+    kv::{self, VisitSource},
+    Level, LevelFilter, Log, Metadata, Record,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::HostConnection;
+
+/// Sent in a [`crate::FrameKind::Log`] frame, tagged with the ID of the request being handled (0 if none)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// The record's key-values, formatted
+    pub fields: Vec<(String, String)>,
+}
+
+impl LogRecord {
+    fn new(record: &Record) -> Self {
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        Self {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            module_path: record.module_path().map(str::to_string),
+            file: record.file().map(str::to_string),
+            line: record.line(),
+            fields: fields.0,
+        }
+    }
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// A [`log::Log`] for plugins - sends each record to the host, which logs it as its own
+/// (with the plugin's name, and the ID of the request being handled).
+///
+/// Records logged before the plugin has connected to the host (i.e. before `main_loop`) are written to stderr instead
+pub struct PluginLogger {
+    level: LevelFilter,
+}
+
+impl PluginLogger {
+    pub fn new(level: LevelFilter) -> Self {
+        Self { level }
+    }
+
+    /// Install a `PluginLogger` as the global logger, passing on records up to `level`
+    pub fn init(level: LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(Self::new(level)))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for PluginLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match HostConnection::current() {
+            Ok(host) => host.log(&LogRecord::new(record)),
+            Err(_) => eprintln!("[{} {}] {}", record.level(), record.target(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log a record the plugin `plugin` sent as the host's own, under its original level and target
+#[cfg(feature = "tracing")]
+pub(crate) fn emit(plugin: &str, request: Option<u64>, record: LogRecord) {
+    let LogRecord { level, target, message, fields, .. } = record;
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!(" {key}={value}"))
+        .collect::<String>();
+    // `tracing` needs its targets and levels up front - so the plugin's target is a field instead
+    macro_rules! emit {
+        ($level:ident) => {
+            tracing::$level!(target: "io_plugin::plugin", plugin, request, target, "{message}{fields}")
+        };
+    }
+    match level {
+        Level::Error => emit!(error),
+        Level::Warn => emit!(warn),
+        Level::Info => emit!(info),
+        Level::Debug => emit!(debug),
+        Level::Trace => emit!(trace),
+    }
+}
+
+/// Log a record the plugin `plugin` sent as the host's own, under its original level and target
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn emit(plugin: &str, request: Option<u64>, record: LogRecord) {
+    if record.level > log::max_level() {
+        return;
+    }
+    let mut fields = record.fields;
+    fields.push(("plugin".to_string(), plugin.to_string()));
+    if let Some(request) = request {
+        fields.push(("request".to_string(), request.to_string()));
+    }
+    log::logger().log(
+        &Record::builder()
+            .level(record.level)
+            .target(&record.target)
+            .args(format_args!("{}", record.message))
+            .module_path(record.module_path.as_deref())
+            .file(record.file.as_deref())
+            .line(record.line)
+            .key_values(&fields)
+            .build(),
+    );
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{CallError, Frame};
+
+/// Upper bounds of the buckets [`Latency`] sorts calls into - slower calls fall into one last, unbounded bucket
+pub const LATENCY_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// How long a method's calls took, as a histogram
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    /// How many calls fell into each of the [`LATENCY_BUCKETS`], then how many were slower than all of them
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl Latency {
+    fn record(&mut self, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64))
+    }
+}
+
+/// What a handle has recorded about calls to one of the plugin's methods.
+///
+/// For a `#[stream]` variant, latency is how long the stream took to open -
+/// while bytes received (and errors which end the stream early) are counted as the stream is consumed
+#[derive(Debug, Clone, Default)]
+pub struct MethodMetrics {
+    pub calls: u64,
+    /// How many calls failed, by the kind of [`CallError`] (see [`CallError::kind`])
+    pub errors: HashMap<&'static str, u64>,
+    /// Bytes written to the plugin, including frame headers
+    pub bytes_sent: u64,
+    /// Bytes read from the plugin, including frame headers
+    pub bytes_received: u64,
+    /// Calls which haven't finished yet
+    pub in_flight: u64,
+    /// How many times calls were retried (see [`crate::RetryPolicy`])
+    pub retries: u64,
+    pub latency: Latency,
+}
+
+tokio::task_local! {
+    static CALL: Call;
+}
+
+/// A call being tracked - frames sent and received within it are counted towards its method
+#[derive(Clone)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub(crate) struct Call {
+    plugin: Arc<Mutex<String>>,
+    method: &'static str,
+    metrics: Arc<Mutex<MethodMetrics>>,
+}
+
+impl Call {
+    /// The call being made, when called from within [`Metrics::track`]
+    pub fn current() -> Option<Self> {
+        CALL.try_with(Self::clone).ok()
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.metrics.lock().unwrap().bytes_sent += bytes as u64;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("io_plugin_bytes_sent_total", &self.labels()).increment(bytes as u64);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.metrics.lock().unwrap().bytes_received += bytes as u64;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("io_plugin_bytes_received_total", &self.labels()).increment(bytes as u64);
+    }
+
+    pub fn failed(&self, kind: &'static str) {
+        *self.metrics.lock().unwrap().errors.entry(kind).or_default() += 1;
+        #[cfg(feature = "metrics")]
+        {
+            let mut labels = self.labels();
+            labels.push(("kind", kind.to_string()));
+            metrics::counter!("io_plugin_call_errors_total", &labels).increment(1);
+        }
+    }
+
+    pub fn retried(&self) {
+        self.metrics.lock().unwrap().retries += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("io_plugin_call_retries_total", &self.labels()).increment(1);
+    }
+
+    fn started(&self) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.calls += 1;
+        metrics.in_flight += 1;
+        #[cfg(feature = "metrics")]
+        {
+            let labels = self.labels();
+            metrics::counter!("io_plugin_calls_total", &labels).increment(1);
+            metrics::gauge!("io_plugin_calls_in_flight", &labels).increment(1);
+        }
+    }
+
+    fn finished(&self, elapsed: Duration) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.latency.record(elapsed);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("io_plugin_call_duration_seconds", &self.labels()).record(elapsed);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn labels(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("plugin", self.plugin.lock().unwrap().clone()),
+            ("method", self.method.to_string()),
+        ]
+    }
+}
+
+/// Count `frame` as sent by the call being made, if it's being tracked
+pub(crate) fn sent(frame: &Frame) {
+    if let Some(call) = Call::current() {
+        call.sent(frame.size());
+    }
+}
+
+/// Count `frame` as received by the call being made, if it's being tracked
+pub(crate) fn received(frame: &Frame) {
+    if let Some(call) = Call::current() {
+        call.received(frame.size());
+    }
+}
+
+/// Marks its call as no longer in flight once dropped - whether the call finished, or was dropped part way
+struct InFlight(Call);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.metrics.lock().unwrap().in_flight -= 1;
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("io_plugin_calls_in_flight", &self.0.labels()).decrement(1);
+    }
+}
+
+/// Per-method metrics of a [`crate::Connection`]'s calls
+#[derive(Default)]
+pub(crate) struct Metrics {
+    methods: Mutex<HashMap<&'static str, Arc<Mutex<MethodMetrics>>>>,
+}
+
+impl Metrics {
+    /// Make `call` to `method` of the plugin labelled `plugin`, recording it
+    pub async fn track<T, E>(
+        &self,
+        plugin: &Arc<Mutex<String>>,
+        method: &'static str,
+        call: impl Future<Output = Result<T, CallError<E>>>,
+    ) -> Result<T, CallError<E>> {
+        let metrics = self.methods.lock().unwrap().entry(method).or_default().clone();
+        let tracked = Call {
+            plugin: plugin.clone(),
+            method,
+            metrics,
+        };
+        tracked.started();
+        let _in_flight = InFlight(tracked.clone());
+        let started = Instant::now();
+        let result = CALL.scope(tracked.clone(), call).await;
+        tracked.finished(started.elapsed());
+        if let Err(err) = &result {
+            tracked.failed(err.kind());
+        }
+        result
+    }
+
+    pub fn snapshot(&self) -> HashMap<&'static str, MethodMetrics> {
+        self.methods
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(method, metrics)| (*method, metrics.lock().unwrap().clone()))
+            .collect()
+    }
+}
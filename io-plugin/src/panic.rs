@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use std::{any::Any, cell::RefCell, sync::Once};
+
+/// What the plugin does once one of its methods has panicked (and the host has been told)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Keep handling requests
+    #[default]
+    Continue,
+    /// Exit with status 101, as an uncaught panic would
+    Exit,
+}
+
+/// Sent in a [`crate::FrameKind::Panic`] frame, in place of a response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Panic {
+    pub message: String,
+    pub location: Option<String>,
+}
+
+thread_local! {
+    /// Where the last panic on this thread happened - the panic's payload doesn't say
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+/// Record the location of each panic, before passing it on to the previous hook (which prints it, by default)
+pub(crate) fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            LOCATION.with(|location| *location.borrow_mut() = info.location().map(ToString::to_string));
+            previous(info);
+        }));
+    });
+}
+
+impl Panic {
+    /// Describe a panic caught on this thread, from its payload
+    pub fn caught(payload: Box<dyn Any + Send>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.to_owned()
+        } else {
+            "Box<dyn Any>".to_string()
+        };
+        Self {
+            message,
+            location: LOCATION.with(|location| location.borrow_mut().take()),
+        }
+    }
+}
@@ -1,10 +1,416 @@
-use std::process::Stdio;
-use std::{io, path::Path};
-use tokio::process::{Child, Command};
+use futures::future::{self, Either};
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::{io, time::Duration};
+use tokio::{
+    process::{Child, Command},
+    sync::{mpsc, watch},
+};
+
+use crate::{CodecId, Connection, StderrLog, StderrMode};
+
+/// How many of its last stderr lines a [`PluginExit`] carries
+pub const EXIT_STDERR_LINES: usize = 20;
+
+/// How long to wait for a plugin to exit once its pipes have closed (and for the rest of its stderr to arrive),
+/// to report how it exited
+pub(crate) const EXIT_GRACE: Duration = Duration::from_millis(500);
 
 pub fn spawn_process(path: &Path) -> Result<Child, io::Error> {
-    Command::new(path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-}
\ No newline at end of file
+    PluginCommand::new(path).spawn()
+}
+
+/// How to start a plugin's process - passed to any of a handle's constructors (as is a plain path, which it converts from).
+///
+/// Like [`std::process::Command`], but with the plugin's stdin and stdout always piped to the host
+#[derive(Debug, Clone)]
+pub struct PluginCommand {
+    program: PathBuf,
+    args: Vec<OsString>,
+    /// Run before the program, with its path and arguments as the last of its own (e.g. a sandboxing wrapper)
+    launcher: Vec<OsString>,
+    /// Variables to set (or remove, if `None`), in order
+    env: Vec<(OsString, Option<OsString>)>,
+    env_clear: bool,
+    current_dir: Option<PathBuf>,
+    #[cfg(unix)]
+    process_group: Option<i32>,
+    stderr: StderrMode,
+    kill_on_drop: bool,
+}
+
+impl PluginCommand {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            launcher: Vec::new(),
+            env: Vec::new(),
+            env_clear: false,
+            current_dir: None,
+            #[cfg(unix)]
+            process_group: None,
+            stderr: StderrMode::default(),
+            kill_on_drop: false,
+        }
+    }
+
+    /// The plugin's executable
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    pub fn args(mut self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Self {
+        self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Start the plugin through `launcher` - running `launcher <args> <program> <program's args>`
+    pub fn launcher(mut self, launcher: impl AsRef<OsStr>, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Self {
+        self.launcher = std::iter::once(launcher.as_ref().to_owned())
+            .chain(args.into_iter().map(|arg| arg.as_ref().to_owned()))
+            .collect();
+        self
+    }
+
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+        self.env.push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+        self
+    }
+
+    pub fn envs(mut self, vars: impl IntoIterator<Item = (impl AsRef<OsStr>, impl AsRef<OsStr>)>) -> Self {
+        for (key, value) in vars {
+            self = self.env(key, value);
+        }
+        self
+    }
+
+    pub fn env_remove(mut self, key: impl AsRef<OsStr>) -> Self {
+        self.env.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    /// Don't pass the host's environment on to the plugin - nor any variables set before this
+    pub fn env_clear(mut self) -> Self {
+        self.env.clear();
+        self.env_clear = true;
+        self
+    }
+
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Put the plugin in the process group `group` - or in a new group of its own, if it's `0`.
+    /// A group of its own keeps signals meant for the host's group (e.g. `SIGINT` from the terminal) from reaching the plugin
+    #[cfg(unix)]
+    pub fn process_group(mut self, group: i32) -> Self {
+        self.process_group = Some(group);
+        self
+    }
+
+    /// What happens to the plugin's stderr - shared with the host's by default
+    pub fn stderr(mut self, stderr: StderrMode) -> Self {
+        self.stderr = stderr;
+        self
+    }
+
+    /// Kill the plugin when its handle is dropped - rather than letting it exit on its own once it notices the pipes have closed
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// Start the plugin's process, with its stdin and stdout piped
+    pub fn spawn(&self) -> Result<Child, io::Error> {
+        let mut command = match self.launcher.split_first() {
+            Some((launcher, args)) => {
+                let mut command = std::process::Command::new(launcher);
+                command.args(args).arg(&self.program);
+                command
+            }
+            None => std::process::Command::new(&self.program),
+        };
+        command.args(&self.args);
+        if self.env_clear {
+            command.env_clear();
+        }
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        #[cfg(unix)]
+        if let Some(group) = self.process_group {
+            std::os::unix::process::CommandExt::process_group(&mut command, group);
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(self.stderr.stdio());
+        Command::from(command).kill_on_drop(self.kill_on_drop).spawn()
+    }
+}
+
+impl From<PathBuf> for PluginCommand {
+    fn from(program: PathBuf) -> Self {
+        Self::new(program)
+    }
+}
+
+impl From<&Path> for PluginCommand {
+    fn from(program: &Path) -> Self {
+        Self::new(program)
+    }
+}
+
+impl From<&str> for PluginCommand {
+    fn from(program: &str) -> Self {
+        Self::new(program)
+    }
+}
+
+/// How a handle talks to its plugin - passed to the handle's `new_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    /// The codec to send messages in - the interface's default if `None`
+    pub codec: Option<CodecId>,
+}
+
+/// How a plugin's process exited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginExit {
+    /// `None` if it was terminated by a signal
+    pub code: Option<i32>,
+    /// The signal which terminated it, on unix
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+    /// The last (at most [`EXIT_STDERR_LINES`]) lines it wrote to stderr, oldest first - empty unless its stderr was being captured
+    pub stderr: Vec<String>,
+}
+
+impl PluginExit {
+    async fn new(status: ExitStatus, stderr: Option<StderrLog>) -> Self {
+        #[cfg(unix)]
+        let (signal, core_dumped) = {
+            use std::os::unix::process::ExitStatusExt;
+            (status.signal(), status.core_dumped())
+        };
+        #[cfg(not(unix))]
+        let (signal, core_dumped) = (None, false);
+        let stderr = match stderr {
+            Some(stderr) => {
+                let _ = tokio::time::timeout(EXIT_GRACE, stderr.finished()).await;
+                let mut lines = stderr.recent();
+                lines.drain(..lines.len().saturating_sub(EXIT_STDERR_LINES));
+                lines
+            }
+            None => Vec::new(),
+        };
+        Self {
+            code: status.code(),
+            signal,
+            core_dumped,
+            stderr,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl Display for PluginExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exited with code {code}")?,
+            (None, Some(signal)) => write!(f, "was terminated by signal {signal}")?,
+            (None, None) => f.write_str("exited")?,
+        }
+        if self.core_dumped {
+            f.write_str(" (core dumped)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a plugin's process is still running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginStatus {
+    Running,
+    Exited(PluginExit),
+    /// The pipes to the plugin have closed - for a handle which doesn't know the plugin's process
+    /// (i.e. one made with `from_stdio`), so can't tell how it exited
+    Disconnected,
+}
+
+/// Sent to the task reaping a plugin's process
+enum Signal {
+    #[cfg(unix)]
+    Terminate,
+    Kill,
+}
+
+impl Signal {
+    fn send(self, child: &mut Child) {
+        match self {
+            #[cfg(unix)]
+            Self::Terminate => {
+                if let Some(pid) = child.id() {
+                    // SAFETY: `pid` is the plugin's, which hasn't been reaped yet (it's only waited for by the task sending this) -
+                    // so it can't have been reused
+                    unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                }
+            }
+            Self::Kill => {
+                let _ = child.start_kill();
+            }
+        }
+    }
+}
+
+/// A plugin's running process - reaped in the background as soon as it exits, so how it exited can be reported
+/// (e.g. in [`crate::CallError::Crashed`]) along with its last stderr lines.
+///
+/// Clones refer to the same process. Once every clone has been dropped, the process is left to run on its own -
+/// or killed, if it was spawned with [`PluginCommand::kill_on_drop`]
+#[derive(Clone)]
+pub struct PluginProcess {
+    id: Option<u32>,
+    signals: mpsc::UnboundedSender<Signal>,
+    exit: watch::Receiver<Option<Result<PluginExit, String>>>,
+}
+
+impl PluginProcess {
+    /// Start reaping `child` in the background - its exit carries the last lines of `stderr`, if it's being captured.
+    /// Must be called within a tokio runtime
+    pub fn watch(child: Child, stderr: Option<StderrLog>) -> Self {
+        let (signals, receiver) = mpsc::unbounded_channel();
+        let (exited, exit) = watch::channel(None);
+        let process = Self {
+            id: child.id(),
+            signals,
+            exit,
+        };
+        tokio::spawn(Self::reap(child, receiver, exited, stderr));
+        process
+    }
+
+    async fn reap(
+        mut child: Child,
+        mut signals: mpsc::UnboundedReceiver<Signal>,
+        exited: watch::Sender<Option<Result<PluginExit, String>>>,
+        stderr: Option<StderrLog>,
+    ) {
+        let status = loop {
+            let signal = match future::select(std::pin::pin!(child.wait()), std::pin::pin!(signals.recv())).await {
+                Either::Left((status, _)) => break status,
+                Either::Right((signal, _)) => signal,
+            };
+            match signal {
+                Some(signal) => signal.send(&mut child),
+                // Every clone has been dropped - dropping the child kills it, if it's set to be killed on drop
+                None => return,
+            }
+        };
+        let exit = match status {
+            Ok(status) => Ok(PluginExit::new(status, stderr).await),
+            Err(err) => Err(err.to_string()),
+        };
+        exited.send_replace(Some(exit));
+    }
+
+    /// The process's ID, as spawned - which may have been reused once it's exited
+    pub fn id(&self) -> Option<u32> {
+        self.id
+    }
+
+    /// Whether the process is still running - without waiting for it
+    pub fn status(&self) -> io::Result<PluginStatus> {
+        match &*self.exit.borrow() {
+            None => Ok(PluginStatus::Running),
+            Some(Ok(exit)) => Ok(PluginStatus::Exited(exit.clone())),
+            Some(Err(err)) => Err(io::Error::other(err.clone())),
+        }
+    }
+
+    /// Wait for the process to exit
+    pub async fn wait(&self) -> io::Result<PluginExit> {
+        let mut exit = self.exit.clone();
+        let exit = exit
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| io::Error::other("The plugin's process is no longer being waited for"))?;
+        match &*exit {
+            Some(Ok(exit)) => Ok(exit.clone()),
+            Some(Err(err)) => Err(io::Error::other(err.clone())),
+            None => unreachable!(),
+        }
+    }
+
+    /// Ask the process to exit, with `SIGTERM`
+    #[cfg(unix)]
+    pub fn terminate(&self) {
+        let _ = self.signals.send(Signal::Terminate);
+    }
+
+    /// Kill the process, without waiting for it to exit
+    pub fn start_kill(&self) {
+        let _ = self.signals.send(Signal::Kill);
+    }
+
+    /// Kill the process, and wait for it to exit
+    pub async fn kill(&self) -> io::Result<PluginExit> {
+        self.start_kill();
+        self.wait().await
+    }
+}
+
+/// Stop the plugin `process` (which `connection` is connected to), escalating as long as it keeps running:
+/// first by asking it to shut down, then (on unix) with `SIGTERM`, then by killing it - waiting up to `timeout` after each.
+///
+/// If the process isn't known, the plugin is only asked to shut down - and fails with [`io::ErrorKind::TimedOut`]
+/// unless it closes the pipes within `timeout`
+pub async fn shutdown_process(process: Option<&PluginProcess>, connection: &Connection, timeout: Duration) -> io::Result<Option<PluginExit>> {
+    let Some(process) = process else {
+        if connection.shutdown().await.is_ok() {
+            tokio::time::timeout(timeout, connection.closed())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("Plugin didn't close the pipes within {timeout:?}")))?;
+        }
+        return Ok(None);
+    };
+    stop(process, connection, timeout).await.map(Some)
+}
+
+async fn stop(process: &PluginProcess, connection: &Connection, timeout: Duration) -> io::Result<PluginExit> {
+    if let PluginStatus::Exited(exit) = process.status()? {
+        return Ok(exit);
+    }
+    if connection.shutdown().await.is_ok() {
+        if let Ok(exit) = tokio::time::timeout(timeout, process.wait()).await {
+            return exit;
+        }
+    }
+    #[cfg(unix)]
+    {
+        process.terminate();
+        if let Ok(exit) = tokio::time::timeout(timeout, process.wait()).await {
+            return exit;
+        }
+    }
+    process.kill().await
+}
@@ -1,64 +1,343 @@
 use serde::{Deserialize, Serialize};
-use serde_cbor::{from_slice, to_vec, Value};
 use std::{
     error::Error,
-    io::{Read, Write as IoWrite},
+    io::{self, Read, Write as IoWrite},
     pin::Pin,
 };
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{panic::Panic, CallError, CodecId, IOPluginError, RemoteError};
+
+pub type BoxedReader = Pin<Box<dyn AsyncRead + Send>>;
+pub type BoxedWriter = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// First byte of every frame - used to detect a desynchronised or foreign stream early
+pub const FRAME_MAGIC: u8 = 0xB5;
+/// Version of the io-plugin wire protocol, sent in every frame header
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Magic byte, protocol version, codec, frame kind, request ID as a big-endian `u64`,
+/// then the payload length as a big-endian `u32`
+pub const HEADER_SIZE: usize = 16;
+/// The largest payload a frame may carry - a header claiming more is rejected before anything is allocated for it
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// What a frame carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FrameKind {
+    Handshake = 0,
+    /// A message from the host, which the plugin must respond to with the same request ID
+    Request = 1,
+    Response = 2,
+    /// One item of a `#[stream]` variant's output
+    StreamItem = 3,
+    /// Marks the end of a `#[stream]` variant's output
+    StreamEnd = 4,
+    /// One item of a `#[client_stream]` variant's streamed argument, tagged with its request's ID
+    Chunk = 5,
+    /// Marks the end of a `#[client_stream]` variant's streamed argument
+    ChunkEnd = 6,
+    /// Sent by the plugin as it consumes each chunk, allowing the host to send another -
+    /// and by the host as it consumes each `#[stream]` item, allowing the plugin to send another
+    ChunkAck = 7,
+    /// A message from the plugin to the host's services, which the host must respond to with the same request ID.
+    /// These IDs are allocated by the plugin, separately from the host's
+    ServiceRequest = 8,
+    ServiceResponse = 9,
+    /// A message from the host to a `#[notify]` variant, which the plugin doesn't respond to
+    Notification = 10,
+    /// Sent by the host when it stops waiting for a request's response - the plugin stops handling it, without responding
+    Cancel = 11,
+    /// Sent by the plugin in place of a response, when handling the request panicked
+    Panic = 12,
+    /// A log record from the plugin, tagged with the ID of the request it was logged while handling (0 if none)
+    Log = 13,
+    /// Sent by the host just before a request made within a `tracing` span, with the span's ID (which the plugin records for correlation - it isn't a trace context)
+    SpanContext = 14,
+    /// Sent by the plugin once it's handled a request which came with a span context, with how long handling it took
+    SpanReport = 15,
+    /// Sent by the host to ask the plugin to exit, once it's finished handling the requests already sent
+    Shutdown = 16,
+}
+
+impl FrameKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Handshake),
+            1 => Some(Self::Request),
+            2 => Some(Self::Response),
+            3 => Some(Self::StreamItem),
+            4 => Some(Self::StreamEnd),
+            5 => Some(Self::Chunk),
+            6 => Some(Self::ChunkEnd),
+            7 => Some(Self::ChunkAck),
+            8 => Some(Self::ServiceRequest),
+            9 => Some(Self::ServiceResponse),
+            10 => Some(Self::Notification),
+            11 => Some(Self::Cancel),
+            12 => Some(Self::Panic),
+            13 => Some(Self::Log),
+            14 => Some(Self::SpanContext),
+            15 => Some(Self::SpanReport),
+            16 => Some(Self::Shutdown),
+            _ => None,
+        }
+    }
+}
+
+/// A single message on the wire, still serialised
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Correlates responses with the request they answer
+    pub id: u64,
+    pub codec: CodecId,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode<T: Serialize>(
+        kind: FrameKind,
+        id: u64,
+        codec: CodecId,
+        message: &T,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self {
+            kind,
+            id,
+            codec,
+            payload: codec.encode(message)?,
+        })
+    }
+
+    pub fn decode<T: for<'a> Deserialize<'a>>(&self) -> Result<T, Box<dyn Error + Send + Sync>> {
+        self.codec.decode(&self.payload)
+    }
+
+    /// Decode the other end's result from a response - failing with [`CallError::Remote`] if it's an error
+    pub fn decode_response<T: for<'a> Deserialize<'a>, E: for<'a> Deserialize<'a>>(&self) -> Result<T, CallError<E>> {
+        if self.kind == FrameKind::Panic {
+            let panic = self.decode::<Panic>().map_err(CallError::decode)?;
+            return Err(CallError::Panicked {
+                message: panic.message,
+                location: panic.location,
+            });
+        }
+        match self.decode::<Result<T, E>>() {
+            Ok(response) => response.map_err(CallError::Remote),
+            // Failures outside of the method (e.g. decoding its request) are sent as a `RemoteError`, whatever its error type
+            Err(err) => match self.decode::<Result<(), RemoteError>>() {
+                Ok(Err(remote)) => Err(CallError::Decode(remote.message)),
+                _ => Err(CallError::decode(err)),
+            },
+        }
+    }
+
+    /// How many bytes the frame takes up on the wire
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.payload.len()
+    }
+
+    /// Prepend the frame header to the payload, so the whole frame can be written in one go
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, IOPluginError> {
+        if self.payload.len() > MAX_FRAME_SIZE {
+            return Err(IOPluginError::FrameTooLarge(self.payload.len()));
+        }
+        let len = self.payload.len() as u32;
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push(FRAME_MAGIC);
+        bytes.push(PROTOCOL_VERSION);
+        bytes.push(self.codec as u8);
+        bytes.push(self.kind as u8);
+        bytes.extend(self.id.to_be_bytes());
+        bytes.extend(len.to_be_bytes());
+        bytes.extend(&self.payload);
+        Ok(bytes)
+    }
+}
+
+/// The fields of a frame header which are common to all protocol versions
+pub(crate) struct Header {
+    pub version: u8,
+    pub codec: u8,
+    pub kind: u8,
+    pub id: u64,
+    pub len: usize,
+}
+
+/// Split a frame header into its fields, only checking the magic byte
+pub(crate) fn parse_header(header: &[u8; HEADER_SIZE]) -> Result<Header, IOPluginError> {
+    let [magic, version, codec, kind, rest @ ..] = *header;
+    if magic != FRAME_MAGIC {
+        return Err(IOPluginError::InvalidFrame(format!(
+            "expected magic byte {FRAME_MAGIC:#04x}, found {magic:#04x}"
+        )));
+    }
+    let (id, len) = rest.split_at(8);
+    Ok(Header {
+        version,
+        codec,
+        kind,
+        id: u64::from_be_bytes(id.try_into().unwrap_or_default()),
+        len: u32::from_be_bytes(len.try_into().unwrap_or_default()) as usize,
+    })
+}
 
-use crate::IOPluginError;
+/// Validate a frame header - all but its kind, since a frame of an unknown kind can be skipped
+fn decode_header(header: &[u8; HEADER_SIZE]) -> Result<(Header, CodecId), IOPluginError> {
+    let header = parse_header(header)?;
+    if header.version != PROTOCOL_VERSION {
+        return Err(IOPluginError::UnsupportedProtocolVersion(header.version));
+    }
+    let codec = CodecId::from_u8(header.codec)
+        .ok_or_else(|| IOPluginError::InvalidFrame(format!("unknown codec {}", header.codec)))?;
+    if header.len > MAX_FRAME_SIZE {
+        return Err(IOPluginError::FrameTooLarge(header.len));
+    }
+    Ok((header, codec))
+}
 
-const BUF_SIZE: usize = 100;
+/// An empty frame for a validated header's payload to be read into - or `None` if its kind is unknown
+fn empty_frame(header: &Header, codec: CodecId) -> Option<Frame> {
+    Some(Frame {
+        kind: FrameKind::from_u8(header.kind)?,
+        id: header.id,
+        codec,
+        payload: vec![0; header.len],
+    })
+}
 
-pub fn io_read<T: for<'a> Deserialize<'a>>(source: &mut (dyn Read + Send)) -> Result<T, Box<dyn Error>> {
-    let mut vec = Vec::<u8>::new();
-    let mut buf = [0; BUF_SIZE];
-    let mut size = BUF_SIZE;
-    while size == BUF_SIZE {
-        size = match source.read(&mut buf) {
-            Ok(0) => Err(IOPluginError::PipeClosed.into()),
-            Err(err) => Err(err.into()),
-            Ok(read) => Ok::<_, Box<dyn Error>>(read),
-        }?;
-        vec.extend(&buf[..size]);
+/// The error for a frame of an unknown kind, once `skipped` of its payload's bytes have been read past
+fn skipped_frame(header: &Header, skipped: u64) -> Box<dyn Error + Send + Sync> {
+    if skipped < header.len as u64 {
+        IOPluginError::PipeClosed.into()
+    } else {
+        IOPluginError::UnknownFrameKind(header.kind).into()
     }
-    Ok(from_slice(vec.as_slice())?)
 }
 
-pub fn io_write<T: Serialize, Write: IoWrite + Send>(sink: &mut Write, message: T) -> Result<(), Box<dyn Error>> {
-    let message = to_vec(&message)?;
-    sink.write_all(&message)?;
+pub(crate) fn map_read_error(err: io::Error) -> Box<dyn Error + Send + Sync> {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        IOPluginError::PipeClosed.into()
+    } else {
+        err.into()
+    }
+}
+
+/// Read a whole frame. A frame of an unknown kind is skipped, failing with [`IOPluginError::UnknownFrameKind`] -
+/// any other error leaves `source` somewhere within a frame, so no more frames can be read from it
+pub fn read_frame(source: &mut (dyn Read + Send)) -> Result<Frame, Box<dyn Error + Send + Sync>> {
+    let mut header = [0; HEADER_SIZE];
+    source.read_exact(&mut header).map_err(map_read_error)?;
+    let (header, codec) = decode_header(&header)?;
+    let Some(mut frame) = empty_frame(&header, codec) else {
+        let skipped = io::copy(&mut source.take(header.len as u64), &mut io::sink())?;
+        return Err(skipped_frame(&header, skipped));
+    };
+    source.read_exact(&mut frame.payload).map_err(map_read_error)?;
+    Ok(frame)
+}
+
+pub fn write_frame<Write: IoWrite + Send>(sink: &mut Write, frame: &Frame) -> Result<(), Box<dyn Error + Send + Sync>> {
+    sink.write_all(&frame.to_bytes()?)?;
     sink.flush()?;
     Ok(())
 }
 
-pub async fn io_read_async<T: for<'a> Deserialize<'a>>(
-    mut source: Pin<&mut (dyn AsyncRead + Send)>,
-) -> Result<T, Box<dyn Error>> {
-    let mut vec = Vec::<u8>::new();
-    let mut buf = [0; BUF_SIZE];
-    let mut size = BUF_SIZE;
-    while size == BUF_SIZE {
-        size = match source.read(&mut buf).await {
-            Ok(0) => Err(IOPluginError::PipeClosed.into()),
-            Err(err) => Err(err.into()),
-            Ok(read) => Ok::<_, Box<dyn Error>>(read),
-        }?;
-        vec.extend(&buf[..size]);
-    }
-    Ok(from_slice(vec.as_slice())?)
+/// Read a whole frame - see [`read_frame`]
+pub async fn read_frame_async(mut source: Pin<&mut (dyn AsyncRead + Send)>) -> Result<Frame, Box<dyn Error + Send + Sync>> {
+    let mut header = [0; HEADER_SIZE];
+    source.read_exact(&mut header).await.map_err(map_read_error)?;
+    let (header, codec) = decode_header(&header)?;
+    let Some(mut frame) = empty_frame(&header, codec) else {
+        let skipped = tokio::io::copy(&mut source.as_mut().take(header.len as u64), &mut tokio::io::sink()).await?;
+        return Err(skipped_frame(&header, skipped));
+    };
+    source.read_exact(&mut frame.payload).await.map_err(map_read_error)?;
+    Ok(frame)
 }
 
-pub async fn io_write_async<T: Serialize, Write: AsyncWrite + Send>(
+pub async fn write_frame_async<Write: AsyncWrite + Send + ?Sized>(
     mut sink: Pin<&mut Write>,
-    message: T,
-) -> Result<(), Box<dyn Error>> {
-    let message = to_vec(&message)?;
-    sink.write_all(&message).await?;
+    frame: &Frame,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    sink.write_all(&frame.to_bytes()?).await?;
     sink.flush().await?;
     Ok(())
 }
 
-#[allow(dead_code)]
-pub type Generic = Value;
+/// A frame's bytes - or no bytes, asking for the pipe to be closed (every frame has a header, so none is empty)
+type QueuedFrame = (Vec<u8>, Option<oneshot::Sender<io::Result<()>>>);
+
+/// Writes whole frames through a shared reference, so frames from concurrent writers never interleave.
+///
+/// Frames are written by a background task - so a frame is always written in full, even if the future writing it is dropped
+pub struct FrameWriter {
+    queue: mpsc::UnboundedSender<QueuedFrame>,
+}
+
+impl FrameWriter {
+    /// Must be called within a tokio runtime
+    pub fn new(mut writer: BoxedWriter) -> Self {
+        let (queue, mut frames) = mpsc::unbounded_channel::<QueuedFrame>();
+        tokio::spawn(async move {
+            while let Some((bytes, written)) = frames.recv().await {
+                if bytes.is_empty() {
+                    let result = writer.shutdown().await;
+                    if let Some(written) = written {
+                        let _ = written.send(result);
+                    }
+                    break;
+                }
+                let result = match writer.write_all(&bytes).await {
+                    Ok(()) => writer.flush().await,
+                    Err(err) => Err(err),
+                };
+                if let Some(written) = written {
+                    let _ = written.send(result);
+                }
+            }
+        });
+        Self { queue }
+    }
+
+    /// Write `frame`, and wait until it's been flushed
+    pub async fn write(&self, frame: &Frame) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (sender, written) = oneshot::channel();
+        self.queue
+            .send((frame.to_bytes()?, Some(sender)))
+            .map_err(|_| IOPluginError::PipeClosed)?;
+        written.await.map_err(|_| IOPluginError::PipeClosed)??;
+        Ok(())
+    }
+
+    /// Close the pipe, once the frames queued before this have been written - frames written from then on fail with
+    /// [`IOPluginError::PipeClosed`]
+    pub async fn close(&self) {
+        let (sender, closed) = oneshot::channel();
+        if self.queue.send((Vec::new(), Some(sender))).is_ok() {
+            let _ = closed.await;
+        }
+    }
+
+    /// Queue `frame` to be written, without waiting for it - for writing from outside of async code (e.g. in `Drop`)
+    pub fn queue(&self, frame: &Frame) -> Result<(), IOPluginError> {
+        self.queue
+            .send((frame.to_bytes()?, None))
+            .map_err(|_| IOPluginError::PipeClosed)
+    }
+
+    /// Send `response` as a `kind` frame, tagged with `request`'s ID and in its codec
+    pub async fn respond<T: Serialize>(
+        &self,
+        request: &Frame,
+        kind: FrameKind,
+        response: &T,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.write(&Frame::encode(kind, request.id, request.codec, response)?)
+            .await
+    }
+}
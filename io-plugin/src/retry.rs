@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use crate::CallError;
+
+/// How calls to `#[idempotent]` variants are retried - set with the handle's `with_retry`.
+///
+/// Calls are retried on the same connection - so only if they time out (the timed-out request is cancelled, leaving the connection usable).
+/// Never if the plugin returned an error or panicked, or writing or reading frames failed - a write which fails part of the way
+/// through a frame leaves the rest of the stream unreadable, and a crashed plugin fails every attempt after it the same way.
+/// Calls to other variants are never retried, since the plugin may have handled them before failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a call is made at most, including the first
+    pub max_attempts: u32,
+    /// How long to wait before the first retry - doubling with each one after it
+    pub backoff: Duration,
+    /// The longest to wait before a retry
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a call which failed with `err` is worth retrying
+    pub fn retries<E>(&self, err: &CallError<E>) -> bool {
+        matches!(err, CallError::Timeout(_))
+    }
+
+    /// How long to wait before retrying, after `attempts` failed attempts
+    pub fn delay(&self, attempts: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
@@ -0,0 +1,445 @@
+use futures::{FutureExt, Stream};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+use crate::{
+    logging::LogRecord,
+    panic::{self, Panic},
+    protocol::{read_frame_async, BoxedReader, BoxedWriter, Frame, FrameKind, FrameWriter},
+    trace::{SpanContext, SpanReport},
+    CallError, CancellationToken, CodecId, IOPluginError, PanicPolicy, CHUNK_WINDOW,
+};
+
+type ChunkSender = mpsc::UnboundedSender<Result<Frame, IOPluginError>>;
+type ChunkReceiver = mpsc::UnboundedReceiver<Result<Frame, IOPluginError>>;
+
+/// A request which the plugin hasn't finished responding to
+struct InFlight {
+    /// Where the chunks of a `#[client_stream]` request go - dropped once the host has sent all of them
+    sender: Option<ChunkSender>,
+    /// Taken by the request's [`RequestStream`]
+    receiver: Option<ChunkReceiver>,
+    cancellation: CancellationToken,
+    /// Each `#[stream]` item sent takes a credit, and each item the host acknowledges returns one
+    credits: Arc<Semaphore>,
+    /// The host's span the request was made in, if it sent one
+    span: Option<SpanContext>,
+    /// The method handling the request, once it's been decoded
+    method: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct ServiceCalls {
+    pending: HashMap<u64, oneshot::Sender<Frame>>,
+    /// Set once the host has closed the pipe - no further responses will arrive
+    closed: bool,
+}
+
+struct Inner {
+    writer: FrameWriter,
+    /// The codec negotiated in the handshake, which requests to the host's services are sent in
+    codec: CodecId,
+    in_flight: std::sync::Mutex<HashMap<u64, InFlight>>,
+    services: std::sync::Mutex<ServiceCalls>,
+    next_service_id: AtomicU64,
+    /// Whether the plugin exits once a request has panicked - see [`PanicPolicy`]
+    exit_on_panic: AtomicBool,
+    /// Cancelled once the plugin has finished serving the host - frames are no longer read from then on
+    closing: CancellationToken,
+}
+
+/// The connection [`HostConnection::current`] returns outside of a request - set by the first [`HostConnection::start`]
+static CURRENT: OnceLock<HostConnection> = OnceLock::new();
+
+tokio::task_local! {
+    static REQUEST: u64;
+    /// The connection the request being handled came through
+    static HOST: HostConnection;
+}
+
+/// The requests (and notifications) the host has sent, in the order it sent them -
+/// closing once the host closes the pipe, or asks the plugin to shut down
+pub type Requests = mpsc::UnboundedReceiver<Frame>;
+
+/// The plugin's end of the connection to the host, shared by every request being handled
+#[derive(Clone)]
+pub struct HostConnection {
+    inner: Arc<Inner>,
+}
+
+impl HostConnection {
+    /// Start reading frames from the host (once the handshake is done) - requests come out of the returned [`Requests`],
+    /// which closes once the host closes the pipe, or sends a [`FrameKind::Shutdown`] frame. Must be called within a tokio runtime
+    pub fn start(reader: BoxedReader, writer: BoxedWriter, codec: CodecId) -> (Self, Requests) {
+        let inner = Arc::new(Inner {
+            writer: FrameWriter::new(writer),
+            codec,
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+            services: std::sync::Mutex::new(ServiceCalls::default()),
+            next_service_id: AtomicU64::new(1),
+            exit_on_panic: AtomicBool::new(false),
+            closing: CancellationToken::new(),
+        });
+        panic::install_hook();
+        let (requests, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(Self::route_frames(reader, requests, inner.clone()));
+        let host = Self { inner };
+        let _ = CURRENT.set(host.clone());
+        (host, receiver)
+    }
+
+    /// The connection the request being handled came through - or outside of a request, the first one the plugin served.
+    /// For calling the host's services from within trait methods
+    pub fn current() -> Result<Self, IOPluginError> {
+        HOST.try_with(Self::clone)
+            .ok()
+            .or_else(|| CURRENT.get().cloned())
+            .ok_or(IOPluginError::InitialisationError(
+                "The plugin isn't connected to a host - call `main_loop` (or `serve`) first".to_string(),
+            ))
+    }
+
+    /// The ID of the request being handled, when called from within one of the plugin's trait methods
+    pub fn current_request() -> Option<u64> {
+        REQUEST.try_with(|id| *id).ok()
+    }
+
+    /// Send `record` to the host, tagged with the request being handled - see [`crate::PluginLogger`]
+    pub(crate) fn log(&self, record: &LogRecord) {
+        let id = Self::current_request().unwrap_or(0);
+        if let Ok(frame) = Frame::encode(FrameKind::Log, id, self.inner.codec, record) {
+            let _ = self.inner.writer.queue(&frame);
+        }
+    }
+
+    /// Record that the current request is being handled by `method` - called by the generated dispatch
+    pub fn record_method(method: &'static str) {
+        let (Ok(host), Some(request)) = (Self::current(), Self::current_request()) else {
+            return;
+        };
+        let mut in_flight = host.inner.in_flight.lock().unwrap();
+        if let Some(request) = in_flight.get_mut(&request) {
+            request.method = Some(method);
+        }
+    }
+
+    /// Stop reading from the host, and close the pipe to it once everything already sent has been written -
+    /// called by the generated `serve`, once the plugin has finished serving the host
+    pub async fn close(&self) {
+        self.inner.closing.cancel();
+        self.inner.writer.close().await;
+    }
+
+    /// The context of the host's span the current request was made in, if the host sent one
+    #[cfg(feature = "tracing")]
+    pub(crate) fn current_span_context() -> Option<SpanContext> {
+        let (host, request) = (Self::current().ok()?, Self::current_request()?);
+        let in_flight = host.inner.in_flight.lock().unwrap();
+        in_flight.get(&request)?.span.clone()
+    }
+
+    pub fn set_panic_policy(&self, policy: PanicPolicy) {
+        self.inner
+            .exit_on_panic
+            .store(policy == PanicPolicy::Exit, Ordering::Relaxed);
+    }
+
+    async fn route_frames(mut reader: BoxedReader, requests: mpsc::UnboundedSender<Frame>, inner: Arc<Inner>) {
+        // Span contexts precede the requests they apply to
+        let mut spans = HashMap::new();
+        // Dropped once the host asks the plugin to shut down - frames are still read, for the requests being handled
+        let mut requests = Some(requests);
+        loop {
+            let Some(frame) = inner.closing.run(read_frame_async(reader.as_mut())).await else {
+                break;
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(err) => match err.downcast_ref::<IOPluginError>() {
+                    Some(IOPluginError::PipeClosed) => break,
+                    // Its payload has been skipped, so the next frame starts where it's expected to
+                    Some(IOPluginError::UnknownFrameKind(_)) => {
+                        log::error!("{err}");
+                        continue;
+                    }
+                    // The rest of the stream can't be trusted to start on a frame boundary
+                    _ => {
+                        log::error!("Closing the connection to the host: {err}");
+                        break;
+                    }
+                },
+            };
+            let mut in_flight = inner.in_flight.lock().unwrap();
+            match frame.kind {
+                FrameKind::Request | FrameKind::Notification if requests.is_none() => {}
+                FrameKind::Request => {
+                    // Any request might be a `#[client_stream]` - its chunks can arrive before it's decoded
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    let request = InFlight {
+                        sender: Some(sender),
+                        receiver: Some(receiver),
+                        cancellation: CancellationToken::new(),
+                        credits: Arc::new(Semaphore::new(CHUNK_WINDOW)),
+                        span: spans.remove(&frame.id),
+                        method: None,
+                    };
+                    in_flight.insert(frame.id, request);
+                    if requests.as_ref().is_some_and(|requests| requests.send(frame).is_err()) {
+                        break;
+                    }
+                }
+                FrameKind::Notification => {
+                    if requests.as_ref().is_some_and(|requests| requests.send(frame).is_err()) {
+                        break;
+                    }
+                }
+                FrameKind::Shutdown => requests = None,
+                FrameKind::Chunk => {
+                    if let Some(sender) = in_flight.get(&frame.id).and_then(|request| request.sender.as_ref()) {
+                        let _ = sender.send(Ok(frame));
+                    }
+                }
+                FrameKind::ChunkEnd => {
+                    if let Some(request) = in_flight.get_mut(&frame.id) {
+                        request.sender = None;
+                    }
+                }
+                FrameKind::ChunkAck => {
+                    if let Some(request) = in_flight.get(&frame.id) {
+                        request.credits.add_permits(1);
+                    }
+                }
+                FrameKind::Cancel => {
+                    if let Some(request) = in_flight.get(&frame.id) {
+                        request.cancellation.cancel();
+                    }
+                }
+                FrameKind::ServiceResponse => {
+                    if let Some(call) = inner.services.lock().unwrap().pending.remove(&frame.id) {
+                        let _ = call.send(frame);
+                    }
+                }
+                FrameKind::SpanContext => {
+                    if let Ok(context) = frame.decode::<SpanContext>() {
+                        spans.insert(frame.id, context);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Dropping the senders wakes every waiting service call
+        let mut services = inner.services.lock().unwrap();
+        services.pending.clear();
+        services.closed = true;
+        drop(services);
+        // Uploads which were cut short end with an error, rather than looking complete -
+        // and streams stop waiting for acknowledgements which won't arrive
+        for request in inner.in_flight.lock().unwrap().values_mut() {
+            if let Some(sender) = request.sender.take() {
+                let _ = sender.send(Err(IOPluginError::PipeClosed));
+            }
+            request.credits.close();
+        }
+    }
+
+    /// Send `response` as a `kind` frame, tagged with `request`'s ID and in its codec
+    pub async fn respond<T: Serialize>(
+        &self,
+        request: &Frame,
+        kind: FrameKind,
+        response: &T,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.inner.writer.respond(request, kind, response).await
+    }
+
+    /// Send `item` as one of a `#[stream]` variant's responses to `request` - once the host has room for it,
+    /// so a plugin producing items faster than the host consumes them is slowed down, rather than buffered
+    pub async fn respond_item<T: Serialize>(&self, request: &Frame, item: &T) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let credits = self
+            .inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .get(&request.id)
+            .map(|request| request.credits.clone());
+        if let Some(credits) = credits {
+            credits.acquire().await.map_err(|_| IOPluginError::PipeClosed)?.forget();
+        }
+        self.respond(request, FrameKind::StreamItem, item).await
+    }
+
+    /// The chunks the host is sending along with `request`, for a `#[client_stream]` variant
+    pub fn request_stream<T: DeserializeOwned>(&self, request: &Frame) -> RequestStream<T> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let _ = sender.send(Err(IOPluginError::Other(
+            "The request's chunks have already been taken".to_string(),
+        )));
+        let chunks = self
+            .inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .get_mut(&request.id)
+            .and_then(|request| request.receiver.take())
+            .unwrap_or(receiver);
+        RequestStream {
+            chunks,
+            host: self.clone(),
+            request: request.id,
+            codec: request.codec,
+            _chunk: PhantomData,
+        }
+    }
+
+    /// Send `message` as a request to the host's services, and wait for the host's response to it
+    pub async fn call_service<T: Serialize, E>(&self, message: &T) -> Result<Frame, CallError<E>> {
+        let id = self.inner.next_service_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Frame::encode(FrameKind::ServiceRequest, id, self.inner.codec, message).map_err(CallError::decode)?;
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut services = self.inner.services.lock().unwrap();
+            if services.closed {
+                return Err(CallError::Crashed(None));
+            }
+            services.pending.insert(id, sender);
+        }
+        if let Err(err) = self.inner.writer.write(&frame).await {
+            self.inner.services.lock().unwrap().pending.remove(&id);
+            return Err(CallError::transport(err));
+        }
+        receiver.await.map_err(|_| CallError::Crashed(None))
+    }
+
+    /// Run `dispatch` (which responds to `request`) - unless the host cancels the request first.
+    /// Errors are logged, since there's nobody else to report them to.
+    /// If `dispatch` panics, the host is sent a [`FrameKind::Panic`] frame in place of the response.
+    ///
+    /// If the host sent the context of the span the request was made in (with the `tracing` feature),
+    /// how long handling it took is reported back to the host
+    pub async fn handle(&self, request: &Frame, dispatch: impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>>) {
+        let (cancellation, span) = self
+            .inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .get(&request.id)
+            .map(|request| (request.cancellation.clone(), request.span.clone()))
+            .unwrap_or_default();
+        let started = Instant::now();
+        let dispatch = cancellation.run(AssertUnwindSafe(dispatch).catch_unwind());
+        let outcome = HOST.scope(self.clone(), REQUEST.scope(request.id, dispatch)).await;
+        let handled = outcome.is_some();
+        match outcome {
+            Some(Ok(Err(err))) => log::error!("{err}"),
+            Some(Err(panic)) => self.panicked(request, Panic::caught(panic)).await,
+            Some(Ok(Ok(()))) | None => {}
+        }
+        let in_flight = self.inner.in_flight.lock().unwrap().remove(&request.id);
+        if let (true, Some(in_flight), Some(_)) = (handled, in_flight, span) {
+            let report = SpanReport {
+                method: in_flight.method.map(str::to_string),
+                elapsed: started.elapsed(),
+            };
+            if let Err(err) = self.respond(request, FrameKind::SpanReport, &report).await {
+                log::error!("{err}");
+            }
+        }
+    }
+
+    async fn panicked(&self, request: &Frame, panic: Panic) {
+        // Notifications have nobody waiting for them - the panic hook has already printed the panic
+        if request.kind == FrameKind::Request {
+            if let Err(err) = self.respond(request, FrameKind::Panic, &panic).await {
+                log::error!("{err}");
+            }
+        }
+        if self.inner.exit_on_panic.load(Ordering::Relaxed) {
+            std::process::exit(101);
+        }
+    }
+}
+
+/// The chunks of a `#[client_stream]` variant's last argument, as the host sends them.
+///
+/// The host only sends a few chunks ahead of what's been consumed from this stream,
+/// so memory use stays bounded regardless of how much is uploaded
+pub struct RequestStream<T> {
+    chunks: ChunkReceiver,
+    host: HostConnection,
+    request: u64,
+    codec: crate::CodecId,
+    _chunk: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Stream for RequestStream<T> {
+    type Item = Result<T, CallError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let chunk = match self.chunks.poll_recv(cx) {
+            Poll::Ready(Some(Ok(chunk))) => chunk,
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err.into()))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        // Let the host send another chunk in place of this one
+        let ack = Frame {
+            kind: FrameKind::ChunkAck,
+            id: self.request,
+            codec: self.codec,
+            payload: Vec::new(),
+        };
+        let _ = self.host.inner.writer.queue(&ack);
+        Poll::Ready(Some(chunk.decode().map_err(CallError::decode)))
+    }
+}
+
+/// Plugin side of an interface declared with `#[io_plugin(concurrency = N)]` - passes `requests` to `dispatch`
+/// (which sends the responses), handling each request on a task of its own, with up to `limit` in flight at once.
+///
+/// Returns once the host has closed the pipe (or asked the plugin to shut down), and every in-flight request has finished
+pub async fn serve_concurrently<F, Fut>(host: &HostConnection, mut requests: Requests, limit: usize, mut dispatch: F)
+where
+    F: FnMut(Frame) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+{
+    let limit = limit.clamp(1, Semaphore::MAX_PERMITS.min(u32::MAX as usize));
+    let slots = Arc::new(Semaphore::new(limit));
+    loop {
+        // The semaphore is never closed
+        let Ok(slot) = slots.clone().acquire_owned().await else {
+            break;
+        };
+        let Some(request) = requests.recv().await else {
+            break;
+        };
+        // `dispatch` takes the request - `handle` only needs its header
+        let header = Frame {
+            kind: request.kind,
+            id: request.id,
+            codec: request.codec,
+            payload: Vec::new(),
+        };
+        let dispatch = dispatch(request);
+        let host = host.clone();
+        tokio::spawn(async move {
+            host.handle(&header, dispatch).await;
+            drop(slot);
+        });
+    }
+    // Each request holds a slot until it's finished
+    let _ = slots.acquire_many(limit as u32).await;
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::VecDeque,
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::ChildStderr,
+    sync::watch,
+};
+
+/// How many of a plugin's most recent stderr lines a [`StderrLog`] keeps
+pub const STDERR_HISTORY: usize = 256;
+/// The longest line a [`StderrLog`] reads, in bytes - longer lines are split into lines of this length
+pub const STDERR_LINE_LIMIT: usize = 8 * 1024;
+
+/// What happens to a plugin's stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StderrMode {
+    /// Shared with the host's stderr
+    #[default]
+    Inherit,
+    /// Piped to the host, which logs each line (see [`StderrLog`])
+    Capture,
+    /// Discarded
+    Null,
+}
+
+impl StderrMode {
+    pub fn stdio(self) -> Stdio {
+        match self {
+            Self::Inherit => Stdio::inherit(),
+            Self::Capture => Stdio::piped(),
+            Self::Null => Stdio::null(),
+        }
+    }
+}
+
+struct Inner {
+    /// The plugin's name, once it's known - its path until then
+    plugin: String,
+    pid: Option<u32>,
+    lines: VecDeque<String>,
+}
+
+/// A plugin's captured stderr - each line is logged with the `io_plugin::stderr` target
+/// (through `tracing` with the `tracing` feature, or `log` otherwise), along with the plugin's name and PID.
+/// The last [`STDERR_HISTORY`] lines are kept, e.g. for explaining a crash
+#[derive(Clone)]
+pub struct StderrLog {
+    inner: Arc<Mutex<Inner>>,
+    /// Becomes `true` once the plugin's stderr has closed
+    finished: watch::Receiver<bool>,
+}
+
+impl StderrLog {
+    /// Start reading `stderr` in the background. Must be called within a tokio runtime
+    pub fn capture(stderr: ChildStderr, plugin: impl ToString, pid: Option<u32>) -> Self {
+        let (finishing, finished) = watch::channel(false);
+        let log = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                plugin: plugin.to_string(),
+                pid,
+                lines: VecDeque::with_capacity(STDERR_HISTORY),
+            })),
+            finished,
+        };
+        tokio::spawn(log.clone().read(stderr, finishing));
+        log
+    }
+
+    pub fn set_plugin(&self, plugin: impl ToString) {
+        self.inner.lock().unwrap().plugin = plugin.to_string();
+    }
+
+    /// The plugin's most recent stderr lines, oldest first
+    pub fn recent(&self) -> Vec<String> {
+        self.inner.lock().unwrap().lines.iter().cloned().collect()
+    }
+
+    /// Completes once the plugin's stderr has closed, and every line of it has been read
+    pub async fn finished(&self) {
+        let _ = self.finished.clone().wait_for(|finished| *finished).await;
+    }
+
+    async fn read(self, stderr: ChildStderr, finishing: watch::Sender<bool>) {
+        let mut stderr = BufReader::new(stderr);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match (&mut stderr).take(STDERR_LINE_LIMIT as u64).read_until(b'\n', &mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+            let (plugin, pid) = {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.lines.len() == STDERR_HISTORY {
+                    inner.lines.pop_front();
+                }
+                inner.lines.push_back(line.clone());
+                (inner.plugin.clone(), inner.pid)
+            };
+            // Emitted without the lock, since whatever it's emitted to may block
+            emit(&plugin, pid, &line);
+        }
+        finishing.send_replace(true);
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn emit(plugin: &str, pid: Option<u32>, line: &str) {
+    tracing::info!(target: "io_plugin::stderr", plugin, pid, "{line}");
+}
+
+#[cfg(not(feature = "tracing"))]
+fn emit(plugin: &str, pid: Option<u32>, line: &str) {
+    log::info!(target: "io_plugin::stderr", plugin = plugin, pid = pid; "{line}");
+}
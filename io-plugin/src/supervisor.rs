@@ -0,0 +1,231 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    future::Future,
+    io,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
+};
+
+use crate::{CallError, Connection, IOPluginError, PluginExit};
+
+/// A handle whose plugin can be spawned again - implemented by every generated handle
+pub trait Respawn: Sized {
+    fn connection(&self) -> &Connection;
+
+    /// Spawn the plugin again the way it was spawned before (killing the old process, if it's still running),
+    /// and handshake with it - returning a handle to the new process, whose connection keeps the old one's settings and metrics
+    fn respawn(&self) -> impl Future<Output = Result<Self, Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Stop the plugin - see [`crate::shutdown_process`]
+    fn shutdown(&self, timeout: Duration) -> impl Future<Output = io::Result<Option<PluginExit>>> + Send;
+}
+
+/// How a [`Supervisor`] restarts its plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// How many restarts are allowed within `window` - the supervisor gives up once its plugin needs more
+    pub max_restarts: u32,
+    pub window: Duration,
+    /// How long to wait before restarting, once the plugin has already been restarted within `window` -
+    /// doubling with each restart in it
+    pub backoff: Duration,
+    /// The longest to wait before a restart
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisorState {
+    Running,
+    /// The plugin exited, and is being restarted - calls wait for it
+    Restarting,
+    /// The plugin needed more restarts than the [`RestartPolicy`] allows - it won't be restarted again
+    GaveUp,
+    /// The plugin has been shut down through [`Supervisor::shutdown`] - it won't be restarted again
+    Stopped,
+}
+
+struct Status {
+    state: SupervisorState,
+    restarts: u64,
+    /// When each restart within the policy's window was attempted
+    recent: VecDeque<Instant>,
+    /// Why the last restart failed, if it did
+    last_error: Option<String>,
+}
+
+/// Keeps a plugin running - restarting it (with backoff) whenever it exits, until it's restarted too often.
+///
+/// Calls go through [`Supervisor::handle`], which waits for a restart in progress:
+/// `supervisor.handle().await?.get_state().await`. A restart replaces the handle with a new one - calls on the old one
+/// (including those which were in flight when the plugin exited) fail with [`crate::CallError::Crashed`], saying how it exited.
+/// A call made through [`Supervisor::call`] which fails because the plugin crashed isn't made again - the supervisor can't tell whether
+/// the plugin handled it before crashing - but the plugin's been restarted by the time it returns, so the caller can retry it if it's safe to
+pub struct Supervisor<H> {
+    /// Only locked for as long as it takes to clone or replace the handle - so a restart never waits for calls, nor calls for a restart
+    handle: RwLock<Arc<H>>,
+    /// Held while restarting the plugin (or shutting it down), so it's only restarted once at a time
+    restarting: tokio::sync::Mutex<()>,
+    policy: RestartPolicy,
+    status: Mutex<Status>,
+}
+
+impl<H: Respawn + Send + Sync + 'static> Supervisor<H> {
+    /// Supervise `handle`'s plugin - it's restarted in the background whenever it exits, for as long as the supervisor is around.
+    /// Must be called within a tokio runtime
+    pub fn new(handle: H, policy: RestartPolicy) -> Arc<Self> {
+        let supervisor = Arc::new(Self {
+            handle: RwLock::new(Arc::new(handle)),
+            restarting: tokio::sync::Mutex::new(()),
+            policy,
+            status: Mutex::new(Status {
+                state: SupervisorState::Running,
+                restarts: 0,
+                recent: VecDeque::new(),
+                last_error: None,
+            }),
+        });
+        tokio::spawn(Self::watch(Arc::downgrade(&supervisor)));
+        supervisor
+    }
+
+    async fn watch(supervisor: Weak<Self>) {
+        loop {
+            let closed = match supervisor.upgrade() {
+                Some(supervisor) => supervisor.current().connection().closed(),
+                None => return,
+            };
+            closed.await;
+            let Some(supervisor) = supervisor.upgrade() else {
+                return;
+            };
+            if supervisor.recover(false).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl<H: Respawn> Supervisor<H> {
+    /// The supervised handle - once its plugin has been restarted, if it's exited.
+    /// Fails once the supervisor has given up on the plugin
+    pub async fn handle(&self) -> Result<Arc<H>, IOPluginError> {
+        let handle = self.current();
+        if !handle.connection().is_closed() {
+            return Ok(handle);
+        }
+        self.recover(false).await?;
+        Ok(self.current())
+    }
+
+    /// Make `call` through the supervised handle: `supervisor.call(|handle| async move { handle.get_state().await }).await`.
+    /// If it fails because the plugin crashed, the plugin is restarted before the [`CallError::Crashed`] is returned -
+    /// the call isn't made again, since the plugin may have handled it before crashing
+    pub async fn call<T, E, F: Future<Output = Result<T, CallError<E>>>>(
+        &self,
+        call: impl FnOnce(Arc<H>) -> F,
+    ) -> Result<T, CallError<E>> {
+        let result = call(self.handle().await?).await;
+        if let Err(CallError::Crashed(_)) = result {
+            // If the supervisor's given up, the next call through it says so
+            let _ = self.recover(false).await;
+        }
+        result
+    }
+
+    /// Restart the plugin now, even if it's still running (e.g. because it's stopped responding).
+    /// Counts towards the [`RestartPolicy`]'s limit
+    pub async fn restart(&self) -> Result<(), IOPluginError> {
+        self.recover(true).await
+    }
+
+    /// Stop supervising the plugin, and shut it down - see [`Respawn::shutdown`]
+    pub async fn shutdown(&self, timeout: Duration) -> io::Result<Option<PluginExit>> {
+        let _restarting = self.restarting.lock().await;
+        self.status.lock().unwrap().state = SupervisorState::Stopped;
+        self.current().shutdown(timeout).await
+    }
+
+    pub fn state(&self) -> SupervisorState {
+        self.status.lock().unwrap().state
+    }
+
+    /// How many times the plugin has been restarted successfully
+    pub fn restarts(&self) -> u64 {
+        self.status.lock().unwrap().restarts
+    }
+
+    /// Why the last attempt to restart the plugin failed, if it did
+    pub fn last_error(&self) -> Option<String> {
+        self.status.lock().unwrap().last_error.clone()
+    }
+
+    pub fn policy(&self) -> RestartPolicy {
+        self.policy
+    }
+
+    fn current(&self) -> Arc<H> {
+        self.handle.read().unwrap().clone()
+    }
+
+    /// Restart the plugin - unless it's running (and `force` isn't set), which it is if it's been restarted while waiting for the lock
+    async fn recover(&self, force: bool) -> Result<(), IOPluginError> {
+        let _restarting = self.restarting.lock().await;
+        let handle = self.current();
+        if !force && !handle.connection().is_closed() {
+            return Ok(());
+        }
+        loop {
+            let delay = self.attempt()?;
+            tokio::time::sleep(delay).await;
+            let result = handle.respawn().await.map_err(|err| err.to_string());
+            let mut status = self.status.lock().unwrap();
+            match result {
+                Ok(respawned) => {
+                    *self.handle.write().unwrap() = Arc::new(respawned);
+                    status.state = SupervisorState::Running;
+                    status.restarts += 1;
+                    status.last_error = None;
+                    return Ok(());
+                }
+                Err(err) => status.last_error = Some(err),
+            }
+        }
+    }
+
+    /// Record an attempt to restart the plugin, returning how long to wait before making it -
+    /// or fail, if the policy doesn't allow another
+    fn attempt(&self) -> Result<Duration, IOPluginError> {
+        let RestartPolicy { max_restarts, window, backoff, max_backoff } = self.policy;
+        let mut status = self.status.lock().unwrap();
+        let now = Instant::now();
+        while status.recent.front().is_some_and(|attempt| now.duration_since(*attempt) > window) {
+            status.recent.pop_front();
+        }
+        if status.state == SupervisorState::Stopped {
+            return Err(IOPluginError::PipeClosed);
+        }
+        if status.state == SupervisorState::GaveUp || status.recent.len() >= max_restarts as usize {
+            status.state = SupervisorState::GaveUp;
+            return Err(IOPluginError::GaveUp { restarts: max_restarts, window });
+        }
+        let delay = match status.recent.len() as u32 {
+            0 => Duration::ZERO,
+            restarts => backoff.saturating_mul(2u32.saturating_pow(restarts - 1)).min(max_backoff),
+        };
+        status.recent.push_back(now);
+        status.state = SupervisorState::Restarting;
+        Ok(delay)
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::{error::Error, future::Future, time::Duration};
+
+/// Sent by the host in a [`crate::FrameKind::SpanContext`] frame, just before the request it applies to.
+///
+/// This isn't a cross-process trace context - `tracing` span IDs are only meaningful within the host's process
+/// (and may be reused once the span closes). The plugin records it on its span for correlating the two processes' output,
+/// and the host logs the plugin's [`SpanReport`] within the span itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SpanContext {
+    /// The ID of the host's span the request was made in
+    pub span: u64,
+}
+
+/// Sent by the plugin in a [`crate::FrameKind::SpanReport`] frame, once it's handled a request which came with a [`SpanContext`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SpanReport {
+    /// The method which handled it, if the request could be decoded
+    pub method: Option<String>,
+    pub elapsed: Duration,
+}
+
+/// The host's span a request was made in - kept until the plugin reports back on it
+#[cfg(feature = "tracing")]
+pub(crate) type HostSpan = tracing::Span;
+#[cfg(not(feature = "tracing"))]
+pub(crate) type HostSpan = ();
+
+/// The span the host is calling the plugin from, if it's in one - and the context to send along with the request
+#[cfg(feature = "tracing")]
+pub(crate) fn current_span() -> Option<(HostSpan, SpanContext)> {
+    let span = tracing::Span::current();
+    let id = span.id()?.into_u64();
+    Some((span, SpanContext { span: id }))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn current_span() -> Option<(HostSpan, SpanContext)> {
+    None
+}
+
+/// Log how long the plugin `plugin` took to handle a request, within the span it was made in
+#[cfg(feature = "tracing")]
+pub(crate) fn report(span: HostSpan, plugin: &str, request: u64, report: SpanReport) {
+    let SpanReport { method, elapsed } = report;
+    span.in_scope(|| tracing::debug!(target: "io_plugin::span", plugin, request, method, ?elapsed, "Plugin handled request"));
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn report(_span: HostSpan, _plugin: &str, _request: u64, _report: SpanReport) {}
+
+/// Run `handling` (the plugin's handling of the current request) in `span` - named after the method handling it
+/// by [`crate::method_span`], which the generated dispatch calls. The span records the request's ID,
+/// and the ID of the host's span the request was made in, if the host sent it - only as a field:
+/// the span isn't made a child of the host's, which lives in another process
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub async fn in_method_span<F>(span: tracing::Span, handling: F) -> F::Output
+where
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>>,
+{
+    use crate::HostConnection;
+    use tracing::Instrument;
+    if let Some(request) = HostConnection::current_request() {
+        span.record("request", request);
+    }
+    if let Some(context) = HostConnection::current_span_context() {
+        span.record("host_span", context.span);
+    }
+    handling.instrument(span).await
+}
+
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+pub async fn in_method_span<F>(_span: (), handling: F) -> F::Output
+where
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>>,
+{
+    handling.await
+}
+
+/// Handle a request in a span named after `$method` - used by the generated dispatch, since a span's name must be a literal
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! method_span {
+    ($method:literal, $handling:expr) => {
+        $crate::in_method_span(
+            $crate::tracing::info_span!(
+                target: "io_plugin",
+                $method,
+                request = $crate::tracing::field::Empty,
+                host_span = $crate::tracing::field::Empty,
+            ),
+            $handling,
+        )
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! method_span {
+    ($method:literal, $handling:expr) => {
+        $crate::in_method_span((), $handling)
+    };
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use common::{connect, Plugin};
+
+#[tokio::test]
+async fn sends_notifications_without_waiting() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    handle.add_to_state(2).await.unwrap();
+    handle.add_to_state(3).await.unwrap();
+    // Requests are handled in order, so the notifications have been by the time this is
+    assert_eq!(handle.get_state().await.unwrap(), 5);
+}
+
+#[tokio::test]
+async fn lets_plugins_call_the_hosts_services() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    assert_eq!(handle.greet("Aroha".to_string()).await.unwrap(), "Kia ora, Aroha!");
+}
+
+#[tokio::test]
+async fn records_metrics_for_each_method() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    handle.echo("one".to_string()).await.unwrap();
+    handle.echo("two".to_string()).await.unwrap();
+    handle.divide(1, 0).await.unwrap_err();
+
+    let metrics = handle.metrics();
+    let echo = &metrics["echo"];
+    assert_eq!(echo.calls, 2);
+    assert!(echo.errors.is_empty());
+    assert!(echo.bytes_sent > 0 && echo.bytes_received > 0);
+    assert_eq!(echo.in_flight, 0);
+    assert!(echo.latency.mean().is_some_and(|mean| mean <= echo.latency.max));
+    assert_eq!(metrics["divide"].errors.get("remote"), Some(&1));
+}
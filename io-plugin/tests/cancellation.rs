@@ -0,0 +1,59 @@
+mod common;
+
+use common::{connect, Plugin};
+use io_plugin::{with_timeout, CallError};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+async fn wait_for(flag: &Arc<AtomicBool>) {
+    let started = Instant::now();
+    while !flag.load(Ordering::SeqCst) {
+        assert!(started.elapsed() < Duration::from_secs(1), "The plugin wasn't told the call was cancelled");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn cancels_calls_which_are_dropped() {
+    let plugin = Plugin::default();
+    let cancelled = plugin.cancelled.clone();
+    let (handle, _serving) = connect(plugin).await;
+
+    assert!(tokio::time::timeout(Duration::from_millis(50), handle.wait_for_cancel()).await.is_err());
+    wait_for(&cancelled).await;
+    // The plugin has stopped handling the cancelled call, so it handles the next one
+    assert_eq!(handle.echo("still here".to_string()).await.unwrap(), "still here");
+}
+
+#[tokio::test]
+async fn cancels_streams_which_are_dropped() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    drop(handle.count(u32::MAX).await.unwrap());
+    assert_eq!(handle.echo("still here".to_string()).await.unwrap(), "still here");
+}
+
+#[tokio::test]
+async fn times_out_calls_on_the_handle() {
+    let plugin = Plugin::default();
+    let cancelled = plugin.cancelled.clone();
+    let (handle, _serving) = connect(plugin).await;
+    let handle = handle.with_timeout(Duration::from_millis(50));
+
+    assert!(matches!(handle.wait_for_cancel().await, Err(CallError::Timeout(timeout)) if timeout == Duration::from_millis(50)));
+    wait_for(&cancelled).await;
+    assert_eq!(handle.get_state().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn times_out_calls_within_with_timeout() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    let handle = handle.with_timeout(Duration::from_secs(10));
+    // The innermost timeout applies
+    let result = with_timeout(Duration::from_millis(50), handle.wait_for_cancel()).await;
+    assert!(matches!(result, Err(CallError::Timeout(timeout)) if timeout == Duration::from_millis(50)));
+}
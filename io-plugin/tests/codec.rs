@@ -0,0 +1,40 @@
+use io_plugin::{CodecId, Frame, FrameKind, IOPluginError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Message {
+    text: String,
+    numbers: Vec<i64>,
+    nested: Option<Box<Message>>,
+}
+
+#[test]
+fn round_trips_messages_in_every_enabled_codec() {
+    let message = Message {
+        text: "Kia ora".to_string(),
+        numbers: vec![-1, 0, i64::MAX],
+        nested: Some(Box::new(Message {
+            text: String::new(),
+            numbers: Vec::new(),
+            nested: None,
+        })),
+    };
+    for codec in [CodecId::Cbor, CodecId::Json, CodecId::MessagePack, CodecId::Bincode] {
+        let frame = Frame::encode(FrameKind::Request, 1, codec, &message);
+        if !codec.is_supported() {
+            assert!(matches!(frame.unwrap_err().downcast_ref(), Some(IOPluginError::UnsupportedCodec(_))));
+            continue;
+        }
+        let frame = frame.unwrap();
+        assert_eq!(frame.codec, codec);
+        assert_eq!(frame.decode::<Message>().unwrap(), message);
+    }
+}
+
+#[test]
+fn reads_codec_ids_from_headers() {
+    for codec in [CodecId::Cbor, CodecId::Json, CodecId::MessagePack, CodecId::Bincode] {
+        assert_eq!(CodecId::from_u8(codec as u8), Some(codec));
+    }
+    assert_eq!(CodecId::from_u8(0), None);
+}
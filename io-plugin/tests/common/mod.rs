@@ -0,0 +1,205 @@
+//! An interface shared by the tests - its plugin is served over an in-memory duplex, rather than from a process
+#![allow(dead_code)]
+use futures::{stream, Stream, StreamExt};
+use io_plugin::{host_services, io_plugin, CancellationToken, RemoteError, RequestStream};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::Display,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{copy_bidirectional, duplex, split},
+    task::JoinHandle,
+};
+
+#[host_services]
+pub enum TestHost {
+    GetConfig(String, Option<String>),
+}
+
+#[io_plugin(host_services = TestHost)]
+pub enum Test {
+    Echo(String, String),
+    SetState(i32, ()),
+    #[idempotent]
+    GetState(i32),
+    /// Sleeps for the given number of milliseconds the first time it's called - responding with how many times it's been called
+    #[idempotent]
+    Slow(u64, u32),
+    #[notify]
+    AddToState(i32, ()),
+    /// Counts from 1 up to the given number
+    #[stream]
+    Count(u32, u32),
+    /// Sums the numbers uploaded
+    #[client_stream]
+    Sum(u32, u64),
+    /// Waits until it's cancelled - setting the plugin's `cancelled` flag
+    WaitForCancel(()),
+    /// Fails with an IO error, caused by another error
+    Fail(()),
+    #[error_type(DivideError)]
+    Divide(i32, i32, i32),
+    Panic(()),
+    /// Greets someone, with the greeting the host has configured
+    Greet(String, String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum DivideError {
+    DivideByZero,
+}
+
+impl Display for DivideError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Can't divide by zero")
+    }
+}
+
+impl Error for DivideError {}
+
+#[derive(Default)]
+pub struct Plugin {
+    pub state: i32,
+    pub slow_calls: u32,
+    /// Set by `on_shutdown`
+    pub shut_down: Arc<AtomicBool>,
+    /// Set once a `wait_for_cancel` call has been cancelled
+    pub cancelled: Arc<AtomicBool>,
+    /// How many items `count` has produced
+    pub counted: Arc<AtomicU32>,
+}
+
+impl TestTrait for Plugin {
+    async fn echo(&mut self, message: String) -> Result<String, RemoteError> {
+        Ok(message)
+    }
+
+    async fn set_state(&mut self, state: i32) -> Result<(), RemoteError> {
+        self.state = state;
+        Ok(())
+    }
+
+    async fn get_state(&mut self) -> Result<i32, RemoteError> {
+        Ok(self.state)
+    }
+
+    async fn slow(&mut self, millis: u64) -> Result<u32, RemoteError> {
+        self.slow_calls += 1;
+        if self.slow_calls == 1 {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+        }
+        Ok(self.slow_calls)
+    }
+
+    async fn add_to_state(&mut self, amount: i32) -> Result<(), RemoteError> {
+        self.state += amount;
+        Ok(())
+    }
+
+    fn count(&mut self, up_to: u32) -> impl Stream<Item = Result<u32, RemoteError>> {
+        let counted = self.counted.clone();
+        stream::iter(1..=up_to).map(move |number| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(number)
+        })
+    }
+
+    async fn sum(&mut self, mut numbers: RequestStream<u32>) -> Result<u64, RemoteError> {
+        let mut sum = 0;
+        while let Some(number) = numbers.next().await {
+            sum += number? as u64;
+        }
+        Ok(sum)
+    }
+
+    async fn wait_for_cancel(&mut self) -> Result<(), RemoteError> {
+        let cancellation = CancellationToken::current().unwrap();
+        let cancelled = self.cancelled.clone();
+        // Spawned, since the method itself stops being polled once it's cancelled
+        tokio::spawn(async move {
+            cancellation.cancelled().await;
+            cancelled.store(true, Ordering::SeqCst);
+        });
+        std::future::pending().await
+    }
+
+    async fn fail(&mut self) -> Result<(), RemoteError> {
+        let cause = io::Error::other("Disk on fire");
+        Err(io::Error::new(io::ErrorKind::NotFound, Caused("Config missing", cause)))?
+    }
+
+    async fn divide(&mut self, dividend: i32, divisor: i32) -> Result<i32, DivideError> {
+        dividend.checked_div(divisor).ok_or(DivideError::DivideByZero)
+    }
+
+    async fn panic(&mut self) -> Result<(), RemoteError> {
+        panic!("Panicked on purpose")
+    }
+
+    async fn greet(&mut self, name: String) -> Result<String, RemoteError> {
+        let greeting = TestHostClient::current()?.get_config("greeting".to_string()).await?;
+        io_plugin::log::info!(name = name.as_str(); "Greeting");
+        Ok(format!("{}, {name}!", greeting.unwrap_or("Hello".to_string())))
+    }
+
+    async fn on_shutdown(&mut self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+}
+
+/// An error with a source
+#[derive(Debug)]
+struct Caused(&'static str, io::Error);
+
+impl Display for Caused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Error for Caused {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.1)
+    }
+}
+
+pub struct Host;
+
+impl TestHostTrait for Host {
+    async fn get_config(&self, key: String) -> Result<Option<String>, RemoteError> {
+        Ok((key == "greeting").then(|| "Kia ora".to_string()))
+    }
+}
+
+pub type Serving = JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>;
+
+/// Serve `plugin` on a task of its own, and connect a handle to it
+pub async fn connect(plugin: Plugin) -> (TestHandle, Serving) {
+    let (host, plugin_end) = duplex(64 * 1024);
+    let (plugin_reader, plugin_writer) = split(plugin_end);
+    let serving = tokio::spawn(plugin.serve(plugin_reader, plugin_writer));
+    let (reader, writer) = split(host);
+    let handle = TestHandle::from_stdio(reader, writer, "test".to_string(), Host).await.unwrap();
+    (handle, serving)
+}
+
+/// Serve `plugin` through a relay, and connect a handle to it - aborting the relay cuts the pipes, like the plugin's process exiting would
+pub async fn connect_relayed(plugin: Plugin) -> (TestHandle, JoinHandle<()>) {
+    let (host, mut host_relay) = duplex(64 * 1024);
+    let (mut plugin_relay, plugin_end) = duplex(64 * 1024);
+    let (plugin_reader, plugin_writer) = split(plugin_end);
+    tokio::spawn(plugin.serve(plugin_reader, plugin_writer));
+    let relay = tokio::spawn(async move {
+        let _ = copy_bidirectional(&mut host_relay, &mut plugin_relay).await;
+    });
+    let (reader, writer) = split(host);
+    let handle = TestHandle::from_stdio(reader, writer, "test".to_string(), Host).await.unwrap();
+    (handle, relay)
+}
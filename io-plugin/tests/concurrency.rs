@@ -0,0 +1,66 @@
+use futures::future::{join, join_all};
+use io_plugin::{io_plugin, RemoteError};
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::io::{duplex, split};
+
+#[io_plugin(concurrency = 4)]
+pub enum Concurrent {
+    /// Sleeps for the given number of milliseconds, then responds with it
+    Sleep(u64, u64),
+}
+
+#[derive(Default, Clone)]
+struct Plugin {
+    running: Arc<AtomicU32>,
+    /// The most calls which were running at once
+    peak: Arc<AtomicU32>,
+}
+
+impl ConcurrentTrait for Plugin {
+    async fn sleep(&self, millis: u64) -> Result<u64, RemoteError> {
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(millis)).await;
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        Ok(millis)
+    }
+}
+
+async fn connect(plugin: Plugin) -> ConcurrentHandle {
+    let (host, plugin_end) = duplex(64 * 1024);
+    let (plugin_reader, plugin_writer) = split(plugin_end);
+    tokio::spawn(plugin.serve(plugin_reader, plugin_writer));
+    let (reader, writer) = split(host);
+    ConcurrentHandle::from_stdio(reader, writer, "concurrent".to_string()).await.unwrap()
+}
+
+#[tokio::test]
+async fn handles_up_to_the_limit_at_once() {
+    let plugin = Plugin::default();
+    let handle = connect(plugin.clone()).await;
+    let started = Instant::now();
+    let responses = join_all((0..8).map(|_| handle.sleep(100))).await;
+    assert!(responses.into_iter().all(|response| response.unwrap() == 100));
+    assert_eq!(plugin.peak.load(Ordering::SeqCst), 4);
+    // Two rounds of four, rather than eight one after the other
+    assert!(started.elapsed() < Duration::from_millis(600));
+}
+
+#[tokio::test]
+async fn matches_responses_to_their_calls() {
+    let handle = connect(Plugin::default()).await;
+    let timed = |millis| {
+        let handle = &handle;
+        async move { (handle.sleep(millis).await.unwrap(), Instant::now()) }
+    };
+    let ((slow, slow_done), (fast, fast_done)) = join(timed(200), timed(10)).await;
+    assert_eq!((slow, fast), (200, 10));
+    // The fast call's response was sent as soon as it was ready, rather than after the slow one's
+    assert!(fast_done < slow_done);
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{connect, DivideError, Plugin, TestHandle};
+use io_plugin::{CallError, RemoteError};
+use std::{error::Error, sync::Arc};
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[tokio::test]
+async fn call_errors_and_calls_can_cross_threads() {
+    assert_send_sync::<CallError<RemoteError>>();
+    assert_send_sync::<CallError<DivideError>>();
+    let (handle, _serving) = connect(Plugin::default()).await;
+    let handle: Arc<TestHandle> = Arc::new(handle);
+    let echoing = tokio::spawn({
+        let handle = handle.clone();
+        async move { handle.echo("spawned".to_string()).await }
+    });
+    assert_eq!(echoing.await.unwrap().unwrap(), "spawned");
+}
+
+#[tokio::test]
+async fn returns_a_variants_own_error_type() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    assert_eq!(handle.divide(6, 3).await.unwrap(), 2);
+    assert!(matches!(handle.divide(1, 0).await, Err(CallError::Remote(DivideError::DivideByZero))));
+}
+
+#[tokio::test]
+async fn preserves_remote_error_details() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    let err = handle.fail().await.unwrap_err();
+    assert_eq!(err.source().unwrap().to_string(), "Disk on fire");
+    let CallError::Remote(err) = err else {
+        panic!("Expected a remote error, got {err:?}");
+    };
+    assert_eq!(err.message, "Config missing");
+    assert_eq!(err.kind.as_deref(), Some("NotFound"));
+    assert_eq!(err.method.as_deref(), Some("fail"));
+}
+
+#[tokio::test]
+async fn reports_panics_and_keeps_serving() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    let Err(CallError::Panicked { message, location }) = handle.panic().await else {
+        panic!("Expected the call to panic");
+    };
+    assert_eq!(message, "Panicked on purpose");
+    assert!(location.is_some_and(|location| location.contains("common")));
+    assert_eq!(handle.echo("still here".to_string()).await.unwrap(), "still here");
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{connect, Plugin, TestTrait};
+use io_plugin::{host_handshake, io_plugin, plugin_handshake, BoxedReader, CodecId, IOPluginError};
+use tokio::io::{duplex, split};
+
+#[io_plugin]
+pub enum Other {
+    Ping(u8, u8),
+}
+
+#[tokio::test]
+async fn rejects_a_plugin_of_another_interface() {
+    let (host, plugin_end) = duplex(64 * 1024);
+    let (plugin_reader, plugin_writer) = split(plugin_end);
+    let serving = tokio::spawn(Plugin::default().serve(plugin_reader, plugin_writer));
+    let (reader, writer) = split(host);
+
+    let err = OtherHandle::from_stdio(reader, writer, "other".to_string()).await.err().unwrap();
+    assert!(matches!(err.downcast_ref(), Some(IOPluginError::IncompatibleInterface { .. })));
+    assert!(serving.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn connects_to_a_plugin_of_the_same_interface() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    assert_eq!(handle.get_state().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn agrees_on_a_codec() {
+    for codec in [CodecId::Cbor, CodecId::Json, CodecId::MessagePack, CodecId::Bincode] {
+        let (host, plugin) = duplex(1024);
+        let (host_reader, mut host_writer) = split(host);
+        let (plugin_reader, mut plugin_writer) = split(plugin);
+        let mut host_reader: BoxedReader = Box::pin(host_reader);
+        let mut plugin_reader: BoxedReader = Box::pin(plugin_reader);
+        let (host, plugin) = tokio::join!(
+            host_handshake(std::pin::Pin::new(&mut host_writer), host_reader.as_mut(), codec, 1),
+            plugin_handshake(plugin_reader.as_mut(), std::pin::Pin::new(&mut plugin_writer), 1),
+        );
+        if codec.is_supported() {
+            host.unwrap();
+            assert_eq!(plugin.unwrap(), codec);
+        } else {
+            // The plugin replies with the default codec instead, which the host doesn't accept
+            assert!(matches!(host.unwrap_err().downcast_ref(), Some(IOPluginError::UnsupportedCodec(unsupported)) if *unsupported == codec));
+            assert!(plugin.is_err());
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// With the `tracing` feature, the host logs plugins' records through `tracing` instead
+#![cfg(not(feature = "tracing"))]
+mod common;
+
+use common::{connect, Plugin};
+use io_plugin::{
+    log::{self, kv::Key, Level, LevelFilter, Log, Metadata, Record},
+    PluginLogger,
+};
+use std::sync::Mutex;
+
+/// A record the host logged on a plugin's behalf
+#[derive(Debug)]
+struct Logged {
+    level: Level,
+    message: String,
+    name: Option<String>,
+    plugin: Option<String>,
+    request: Option<String>,
+}
+
+/// Both ends' logger, since they share a process - the plugin's records (which are yet to have a `plugin` key)
+/// are passed to a [`PluginLogger`], and the ones the host logs on its behalf are recorded
+struct Recorder {
+    plugin: PluginLogger,
+    logged: Mutex<Vec<Logged>>,
+}
+
+impl Log for Recorder {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let value = |key| record.key_values().get(Key::from_str(key)).map(|value| value.to_string());
+        if value("plugin").is_none() {
+            return self.plugin.log(record);
+        }
+        self.logged.lock().unwrap().push(Logged {
+            level: record.level(),
+            message: record.args().to_string(),
+            name: value("name"),
+            plugin: value("plugin"),
+            request: value("request"),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+#[tokio::test]
+async fn forwards_plugin_records_to_the_host() {
+    let recorder: &'static Recorder = Box::leak(Box::new(Recorder {
+        plugin: PluginLogger::new(LevelFilter::Info),
+        logged: Mutex::new(Vec::new()),
+    }));
+    log::set_logger(recorder).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let (handle, _serving) = connect(Plugin::default()).await;
+    handle.greet("Aroha".to_string()).await.unwrap();
+    // Logs are sent ahead of the response, so they've been logged by now
+    let logged = recorder.logged.lock().unwrap();
+    let [greeting] = logged.as_slice() else {
+        panic!("Expected one record, got {logged:?}");
+    };
+    assert_eq!(greeting.level, Level::Info);
+    assert_eq!(greeting.message, "Greeting");
+    assert_eq!(greeting.name.as_deref(), Some("Aroha"));
+    assert_eq!(greeting.plugin.as_deref(), Some("test"));
+    assert!(greeting.request.is_some());
+}
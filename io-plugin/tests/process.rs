@@ -0,0 +1,54 @@
+#![cfg(unix)]
+mod common;
+
+use common::{Host, TestHandle};
+use io_plugin::{IOPluginError, PluginCommand, PluginProcess, StderrMode};
+use tokio::io::AsyncReadExt;
+
+/// Run `command`, returning what it wrote to stdout
+async fn output(command: PluginCommand) -> String {
+    let mut child = command.spawn().unwrap();
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).await.unwrap();
+    child.wait().await.unwrap();
+    output.trim_end().to_string()
+}
+
+#[tokio::test]
+async fn spawns_plugins_as_configured() {
+    let command = PluginCommand::new("/bin/sh")
+        .args(["-c", "echo \"$GREETING from $(pwd)\""])
+        .env("GREETING", "Kia ora")
+        .current_dir("/");
+    assert_eq!(output(command).await, "Kia ora from /");
+
+    let command = PluginCommand::new("/bin/sh").args(["-c", "echo ${HOME:-no home}"]).env_clear();
+    assert_eq!(output(command).await, "no home");
+
+    let command = PluginCommand::new("echo launched").launcher("/bin/sh", ["-c"]);
+    assert_eq!(output(command).await, "launched");
+}
+
+#[tokio::test]
+async fn reports_how_plugins_exit() {
+    let child = PluginCommand::new("/bin/sh").args(["-c", "exit 3"]).spawn().unwrap();
+    let exit = PluginProcess::watch(child, None).wait().await.unwrap();
+    assert_eq!((exit.code, exit.signal, exit.success()), (Some(3), None, false));
+
+    let child = PluginCommand::new("/bin/sh").args(["-c", "kill -KILL $$"]).spawn().unwrap();
+    let exit = PluginProcess::watch(child, None).wait().await.unwrap();
+    assert_eq!((exit.code, exit.signal), (None, Some(9)));
+}
+
+#[tokio::test]
+async fn reports_a_plugin_which_exits_before_the_handshake() {
+    let command = PluginCommand::new("/bin/sh")
+        .args(["-c", "echo 'Missing config' >&2; exit 2"])
+        .stderr(StderrMode::Capture);
+    let err = TestHandle::new(command, "test".to_string(), Host).await.err().unwrap();
+    let Some(IOPluginError::Exited(exit)) = err.downcast_ref() else {
+        panic!("Expected the plugin's exit, got {err}");
+    };
+    assert_eq!(exit.code, Some(2));
+    assert_eq!(exit.stderr, ["Missing config"]);
+}
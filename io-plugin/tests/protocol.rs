@@ -0,0 +1,106 @@
+use io_plugin::{read_frame_async, BoxedReader, write_frame_async, CodecId, Frame, FrameKind, IOPluginError, MAX_FRAME_SIZE};
+use std::pin::Pin;
+use tokio::io::{duplex, AsyncWriteExt};
+
+fn header(kind: u8, id: u64, len: u32) -> Vec<u8> {
+    let mut header = vec![0xB5, 1, CodecId::default() as u8, kind];
+    header.extend(id.to_be_bytes());
+    header.extend(len.to_be_bytes());
+    header
+}
+
+#[tokio::test]
+async fn skips_frames_of_unknown_kinds() {
+    let (mut writer, reader) = duplex(1024);
+    let mut reader: BoxedReader = Box::pin(reader);
+    let mut unknown = header(200, 1, 5);
+    unknown.extend(b"hello");
+    writer.write_all(&unknown).await.unwrap();
+    let frame = Frame::encode(FrameKind::Response, 2, CodecId::default(), &"after").unwrap();
+    write_frame_async(Pin::new(&mut writer), &frame).await.unwrap();
+
+    let err = read_frame_async(reader.as_mut()).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(IOPluginError::UnknownFrameKind(200))));
+    let frame = read_frame_async(reader.as_mut()).await.unwrap();
+    assert_eq!((frame.kind, frame.id), (FrameKind::Response, 2));
+    assert_eq!(frame.decode::<String>().unwrap(), "after");
+}
+
+#[tokio::test]
+async fn rejects_frames_with_bad_magic() {
+    let (mut writer, reader) = duplex(1024);
+    let mut reader: BoxedReader = Box::pin(reader);
+    let mut frame = header(FrameKind::Response as u8, 1, 0);
+    frame[0] = 0;
+    writer.write_all(&frame).await.unwrap();
+
+    let err = read_frame_async(reader.as_mut()).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(IOPluginError::InvalidFrame(_))));
+}
+
+/// Write `frame` through a pipe with a small buffer, and read it back out
+async fn round_trip(frame: &Frame) -> Frame {
+    let (mut writer, reader) = duplex(64);
+    let mut reader: BoxedReader = Box::pin(reader);
+    let write = write_frame_async(Pin::new(&mut writer), frame);
+    let read = read_frame_async(reader.as_mut());
+    let (written, read) = futures::join!(write, read);
+    written.unwrap();
+    read.unwrap()
+}
+
+#[tokio::test]
+async fn round_trips_payloads_of_multiples_of_100_bytes() {
+    for size in (0..=2000).step_by(100).chain([10_000, 100_000]) {
+        let payload = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let frame = Frame {
+            kind: FrameKind::Request,
+            id: size as u64,
+            codec: CodecId::default(),
+            payload,
+        };
+        let read = round_trip(&frame).await;
+        assert_eq!((read.kind, read.id, read.codec), (frame.kind, frame.id, frame.codec));
+        assert_eq!(read.payload, frame.payload, "payload of {size} bytes");
+    }
+}
+
+#[tokio::test]
+async fn reassembles_split_writes() {
+    let frame = Frame::encode(FrameKind::StreamItem, 7, CodecId::default(), &vec![3u64; 50]).unwrap();
+    let mut bytes = header(FrameKind::StreamItem as u8, 7, frame.payload.len() as u32);
+    bytes.extend(&frame.payload);
+    let (mut writer, reader) = duplex(1024);
+    let mut reader: BoxedReader = Box::pin(reader);
+    let write = async move {
+        // One byte at a time through the header, then in uneven pieces
+        for piece in bytes[..16].chunks(1).chain(bytes[16..].chunks(7)) {
+            writer.write_all(piece).await.unwrap();
+            writer.flush().await.unwrap();
+            tokio::task::yield_now().await;
+        }
+    };
+    let (_, read) = futures::join!(write, read_frame_async(reader.as_mut()));
+    let read = read.unwrap();
+    assert_eq!((read.kind, read.id), (FrameKind::StreamItem, 7));
+    assert_eq!(read.decode::<Vec<u64>>().unwrap(), vec![3; 50]);
+}
+
+#[tokio::test]
+async fn rejects_frames_larger_than_the_limit() {
+    let (mut writer, reader) = duplex(1024);
+    let mut reader: BoxedReader = Box::pin(reader);
+    let len = MAX_FRAME_SIZE as u32 + 1;
+    writer.write_all(&header(FrameKind::Request as u8, 1, len)).await.unwrap();
+    let err = read_frame_async(reader.as_mut()).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(&IOPluginError::FrameTooLarge(size)) if size == len as usize));
+
+    let frame = Frame {
+        kind: FrameKind::Request,
+        id: 1,
+        codec: CodecId::default(),
+        payload: vec![0; MAX_FRAME_SIZE + 1],
+    };
+    let err = write_frame_async(Pin::new(&mut writer), &frame).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(IOPluginError::FrameTooLarge(_))));
+}
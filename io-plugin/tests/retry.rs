@@ -0,0 +1,42 @@
+mod common;
+
+use common::{connect, connect_relayed, Plugin};
+use io_plugin::{CallError, PluginStatus, RetryPolicy};
+use std::time::{Duration, Instant};
+
+fn policy(backoff: Duration) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        backoff,
+        max_backoff: backoff,
+    }
+}
+
+#[tokio::test]
+async fn retries_calls_which_time_out() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    let handle = handle
+        .with_timeout(Duration::from_millis(100))
+        .with_retry(policy(Duration::from_millis(10)));
+    // The first attempt times out, and is cancelled - the second is answered straight away
+    assert_eq!(handle.slow(1000).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn doesnt_retry_calls_once_the_plugin_has_crashed() {
+    let (handle, relay) = connect_relayed(Plugin::default()).await;
+    let handle = handle.with_retry(policy(Duration::from_secs(1)));
+    assert_eq!(handle.get_state().await.unwrap(), 0);
+
+    relay.abort();
+    let started = Instant::now();
+    while !matches!(handle.status(), Ok(PluginStatus::Disconnected)) {
+        assert!(started.elapsed() < Duration::from_secs(1), "The handle didn't notice the plugin crash");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Every retry would fail the same way, so it fails without waiting for one
+    let started = Instant::now();
+    assert!(matches!(handle.get_state().await, Err(CallError::Crashed(None))));
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
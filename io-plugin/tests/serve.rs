@@ -0,0 +1,36 @@
+mod common;
+
+use common::{connect, Plugin, TestTrait};
+use std::{sync::atomic::Ordering, time::Duration};
+use tokio::io::{duplex, split, AsyncWriteExt};
+
+#[tokio::test]
+async fn serves_a_host_over_a_duplex() {
+    let plugin = Plugin::default();
+    let shut_down = plugin.shut_down.clone();
+    let (mut handle, serving) = connect(plugin).await;
+
+    assert_eq!(handle.echo("hello".to_string()).await.unwrap(), "hello");
+    handle.set_state(3).await.unwrap();
+    assert_eq!(handle.get_state().await.unwrap(), 3);
+
+    // Without a process, there's no exit to report
+    assert!(handle.shutdown(Duration::from_secs(1)).await.unwrap().is_none());
+    serving.await.unwrap().unwrap();
+    assert!(shut_down.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn stops_serving_once_the_handle_is_dropped() {
+    let (handle, serving) = connect(Plugin::default()).await;
+    drop(handle);
+    tokio::time::timeout(Duration::from_secs(1), serving).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn fails_without_a_handshake() {
+    let (mut host, plugin_end) = duplex(1024);
+    let (reader, writer) = split(plugin_end);
+    host.write_all(&[0; 16]).await.unwrap();
+    assert!(Plugin::default().serve(reader, writer).await.is_err());
+}
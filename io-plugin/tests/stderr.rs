@@ -0,0 +1,21 @@
+#![cfg(unix)]
+use io_plugin::{StderrLog, STDERR_LINE_LIMIT};
+use std::process::Stdio;
+use tokio::process::Command;
+
+#[tokio::test]
+async fn splits_lines_longer_than_the_limit() {
+    let mut child = Command::new("sh")
+        .args(["-c", "head -c 20000 /dev/zero | tr '\\0' a >&2; echo >&2; echo short >&2"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = StderrLog::capture(child.stderr.take().unwrap(), "sh", child.id());
+    stderr.finished().await;
+    child.wait().await.unwrap();
+
+    let lines = stderr.recent();
+    let lengths = lines.iter().map(String::len).collect::<Vec<_>>();
+    assert_eq!(lengths, [STDERR_LINE_LIMIT, STDERR_LINE_LIMIT, 20000 - 2 * STDERR_LINE_LIMIT, 5]);
+    assert_eq!(lines[3], "short");
+}
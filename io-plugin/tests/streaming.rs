@@ -0,0 +1,36 @@
+mod common;
+
+use common::{connect, Plugin};
+use futures::{stream, StreamExt};
+use io_plugin::CHUNK_WINDOW;
+use std::{sync::atomic::Ordering, time::Duration};
+
+#[tokio::test]
+async fn streams_items_in_order() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    let items = handle.count(100).await.unwrap();
+    let items = items.map(Result::unwrap).collect::<Vec<_>>().await;
+    assert_eq!(items, (1..=100).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn only_streams_a_window_ahead_of_the_host() {
+    let plugin = Plugin::default();
+    let counted = plugin.counted.clone();
+    let (handle, _serving) = connect(plugin).await;
+    let mut items = handle.count(100).await.unwrap();
+    assert_eq!(items.next().await.unwrap().unwrap(), 1);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // The item consumed, the window in flight, and the one waiting for room in it
+    assert!(counted.load(Ordering::SeqCst) as usize <= CHUNK_WINDOW + 2);
+    let rest = items.map(Result::unwrap).collect::<Vec<_>>().await;
+    assert_eq!(rest, (2..=100).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn uploads_a_client_stream() {
+    let (handle, _serving) = connect(Plugin::default()).await;
+    assert_eq!(handle.sum(stream::iter(1..=1000)).await.unwrap(), 500500);
+    assert_eq!(handle.sum(stream::empty()).await.unwrap(), 0);
+}
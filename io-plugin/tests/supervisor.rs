@@ -0,0 +1,108 @@
+mod common;
+
+use common::{connect_relayed, Plugin, TestHandle};
+use io_plugin::{CallError, Connection, IOPluginError, PluginExit, RestartPolicy, Respawn, Supervisor};
+use std::{
+    error::Error,
+    io,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// A plugin served through a relay - "respawned" by cutting the relay, and serving a new plugin through another
+struct Relayed {
+    handle: TestHandle,
+    relay: JoinHandle<()>,
+}
+
+impl Relayed {
+    async fn new() -> Self {
+        let (handle, relay) = connect_relayed(Plugin::default()).await;
+        Self { handle, relay }
+    }
+
+    /// Cut the pipes, and wait for the handle to notice
+    async fn crash(&self) {
+        self.relay.abort();
+        self.handle.connection.closed().await;
+    }
+}
+
+impl Respawn for Relayed {
+    fn connection(&self) -> &Connection {
+        &self.handle.connection
+    }
+
+    async fn respawn(&self) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        self.relay.abort();
+        let mut respawned = Self::new().await;
+        respawned.handle.connection.inherit(&self.handle.connection);
+        Ok(respawned)
+    }
+
+    async fn shutdown(&self, timeout: Duration) -> io::Result<Option<PluginExit>> {
+        io_plugin::shutdown_process(None, &self.handle.connection, timeout).await
+    }
+}
+
+fn policy() -> RestartPolicy {
+    RestartPolicy {
+        max_restarts: 2,
+        backoff: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn restarts_a_crashed_plugin() {
+    let supervisor = Supervisor::new(Relayed::new().await, policy());
+    let crashed = supervisor.handle().await.unwrap();
+    crashed.handle.set_state(3).await.unwrap();
+    crashed.crash().await;
+
+    let restarted = supervisor.handle().await.unwrap();
+    assert_eq!(restarted.handle.get_state().await.unwrap(), 0);
+    assert_eq!(supervisor.restarts(), 1);
+    // The old handle isn't revived along with the plugin
+    assert!(matches!(crashed.handle.get_state().await, Err(CallError::Crashed(_))));
+}
+
+#[tokio::test]
+async fn restarts_while_handles_are_held() {
+    let supervisor = Supervisor::new(Relayed::new().await, policy());
+    let held = supervisor.handle().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), supervisor.restart()).await.unwrap().unwrap();
+    assert!(matches!(held.handle.get_state().await, Err(CallError::Crashed(_))));
+    assert_eq!(supervisor.handle().await.unwrap().handle.get_state().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn restarts_the_plugin_without_repeating_a_crashed_call() {
+    let supervisor = Supervisor::new(Relayed::new().await, policy());
+    let mut attempts = 0;
+    let result = supervisor
+        .call(|relayed| {
+            attempts += 1;
+            async move {
+                relayed.crash().await;
+                relayed.handle.set_state(3).await
+            }
+        })
+        .await;
+    assert!(matches!(result, Err(CallError::Crashed(_))));
+    assert_eq!(attempts, 1);
+    assert_eq!(supervisor.restarts(), 1);
+    let state = supervisor.call(|relayed| async move { relayed.handle.get_state().await }).await;
+    assert_eq!(state.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn gives_up_on_a_plugin_which_keeps_crashing() {
+    let supervisor = Supervisor::new(Relayed::new().await, policy());
+    for _ in 0..2 {
+        supervisor.handle().await.unwrap().crash().await;
+        supervisor.handle().await.unwrap();
+    }
+    supervisor.handle().await.unwrap().crash().await;
+    assert!(matches!(supervisor.handle().await, Err(IOPluginError::GaveUp { .. })));
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "tracing")]
+mod common;
+
+use common::{connect, Plugin};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event, Instrument, Metadata, Subscriber,
+};
+use tracing_core::span::Current;
+
+/// A span created, and the `host_span` it was given
+type Recorded = (&'static Metadata<'static>, Option<u64>);
+
+/// Records the names of the spans created, and the `host_span` each was given - along with the spans entered,
+/// since the host only sends the context of the current span
+#[derive(Default, Clone)]
+struct Spans {
+    spans: Arc<Mutex<Vec<Recorded>>>,
+    entered: Arc<Mutex<Vec<Id>>>,
+}
+
+struct HostSpan(Option<u64>);
+
+impl Visit for HostSpan {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "host_span" {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl Subscriber for Spans {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((span.metadata(), None));
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut host_span = HostSpan(None);
+        values.record(&mut host_span);
+        if let Some(host_span) = host_span.0 {
+            self.spans.lock().unwrap()[span.into_u64() as usize - 1].1 = Some(host_span);
+        }
+    }
+
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        self.entered.lock().unwrap().push(span.clone());
+    }
+
+    fn exit(&self, _span: &Id) {
+        self.entered.lock().unwrap().pop();
+    }
+
+    fn current_span(&self) -> Current {
+        match self.entered.lock().unwrap().last() {
+            Some(span) => Current::new(span.clone(), self.spans.lock().unwrap()[span.into_u64() as usize - 1].0),
+            None => Current::none(),
+        }
+    }
+}
+
+// Single-threaded, so the plugin's task sees the subscriber too
+#[tokio::test(flavor = "current_thread")]
+async fn handles_requests_in_spans_named_after_their_method() {
+    let spans = Spans::default();
+    let _default = tracing::subscriber::set_default(spans.clone());
+    let (mut handle, serving) = connect(Plugin::default()).await;
+
+    let calling = tracing::info_span!("calling");
+    let host_span = calling.id().unwrap().into_u64();
+    handle.echo("hello".to_string()).instrument(calling).await.unwrap();
+    // Outside of a span, there's no host span to record
+    handle.get_state().await.unwrap();
+
+    handle.shutdown(Duration::from_secs(1)).await.unwrap();
+    serving.await.unwrap().unwrap();
+    let spans = spans.spans.lock().unwrap();
+    let spans = spans.iter().map(|(metadata, host_span)| (metadata.name(), *host_span)).collect::<Vec<_>>();
+    assert!(spans.contains(&("echo", Some(host_span))), "{spans:?}");
+    assert!(spans.contains(&("get_state", None)), "{spans:?}");
+}